@@ -9,12 +9,11 @@ pub fn main() -> Result<(), Box<dyn Error>> {
     let img = image::open("test.png")?;
     let job = PrintJob {
         no_pages: 1,
-        image: img,
-        media: Media::C62,
         high_dpi: false,
         compressed: false,
         quality_priority: true,
         cut_behaviour: CutBehavior::CutEach,
+        ..PrintJob::new(img, Media::C62)
     };
     let data = job.compile()?;
     let mut file = File::create("test.bin")?;
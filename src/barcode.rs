@@ -0,0 +1,347 @@
+//! Rendering of barcodes that fill the printable area of a media
+use image::{imageops, DynamicImage, GrayImage, Luma};
+use qrcode::{Color, QrCode};
+
+use crate::{
+    error::BQLError,
+    media::{Media, MediaSettings, MediaType},
+};
+
+/// This enum specifies the supported barcode symbologies.
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
+pub enum Symbology {
+    /// Code 128 using code set B, i.e. printable ASCII characters
+    Code128,
+    /// QR code with medium error correction
+    Qr,
+}
+
+/// The amount of blank modules required on each side of a Code 128 barcode.
+const CODE128_QUIET_ZONE: u32 = 10;
+/// The amount of blank modules required around a QR code.
+const QR_QUIET_ZONE: u32 = 4;
+
+/// The bar and space widths of all Code 128 symbols, starting with a bar. The last three entries
+/// are the start symbols for code sets A, B and C.
+const CODE128_PATTERNS: [&[u8; 6]; 106] = [
+    b"212222", b"222122", b"222221", b"121223", b"121322", b"131222", b"122213", b"122312",
+    b"132212", b"221213", b"221312", b"231212", b"112232", b"122132", b"122231", b"113222",
+    b"123122", b"123221", b"223211", b"221132", b"221231", b"213212", b"223112", b"312131",
+    b"311222", b"321122", b"321221", b"312212", b"322112", b"322211", b"212123", b"212321",
+    b"232121", b"111323", b"131123", b"131321", b"112313", b"132113", b"132311", b"211313",
+    b"231113", b"231311", b"112133", b"112331", b"132131", b"113123", b"113321", b"133121",
+    b"313121", b"211331", b"231131", b"213113", b"213311", b"213131", b"311123", b"311321",
+    b"331121", b"312113", b"312311", b"332111", b"314111", b"221411", b"431111", b"111224",
+    b"111422", b"121124", b"121421", b"141122", b"141221", b"112214", b"112412", b"122114",
+    b"122411", b"142112", b"142211", b"241211", b"221114", b"413111", b"241112", b"134111",
+    b"111242", b"121142", b"121241", b"114212", b"124112", b"124211", b"411212", b"421112",
+    b"421211", b"212141", b"214121", b"412121", b"111143", b"111341", b"131141", b"114113",
+    b"114311", b"411113", b"411311", b"113141", b"114131", b"311141", b"411131", b"211412",
+    b"211214", b"211232",
+];
+const CODE128_START_B: usize = 104;
+const CODE128_STOP: &[u8; 7] = b"2331112";
+
+/// The maximum scale of the built-in font, i.e. glyphs are at most 28 dots high.
+const MAX_TEXT_SCALE: u32 = 4;
+/// The width of a glyph of the built-in font including the space to the next glyph, unscaled.
+const GLYPH_ADVANCE: u32 = 6;
+/// The amount of blank rows between the barcode and the text, unscaled.
+const TEXT_PADDING: u32 = 2;
+/// The height of the line of text below a barcode including padding, unscaled.
+const TEXT_LINE_HEIGHT: u32 = 10;
+
+/// The columns of a 5x7 font for the printable ASCII characters, starting with the space. The
+/// least significant bit of each column is the top row.
+const FONT_5X7: [[u8; 5]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00],
+    [0x00, 0x00, 0x5f, 0x00, 0x00],
+    [0x00, 0x07, 0x00, 0x07, 0x00],
+    [0x14, 0x7f, 0x14, 0x7f, 0x14],
+    [0x24, 0x2a, 0x7f, 0x2a, 0x12],
+    [0x23, 0x13, 0x08, 0x64, 0x62],
+    [0x36, 0x49, 0x55, 0x22, 0x50],
+    [0x00, 0x05, 0x03, 0x00, 0x00],
+    [0x00, 0x1c, 0x22, 0x41, 0x00],
+    [0x00, 0x41, 0x22, 0x1c, 0x00],
+    [0x14, 0x08, 0x3e, 0x08, 0x14],
+    [0x08, 0x08, 0x3e, 0x08, 0x08],
+    [0x00, 0x50, 0x30, 0x00, 0x00],
+    [0x08, 0x08, 0x08, 0x08, 0x08],
+    [0x00, 0x60, 0x60, 0x00, 0x00],
+    [0x20, 0x10, 0x08, 0x04, 0x02],
+    [0x3e, 0x51, 0x49, 0x45, 0x3e],
+    [0x00, 0x42, 0x7f, 0x40, 0x00],
+    [0x42, 0x61, 0x51, 0x49, 0x46],
+    [0x21, 0x41, 0x45, 0x4b, 0x31],
+    [0x18, 0x14, 0x12, 0x7f, 0x10],
+    [0x27, 0x45, 0x45, 0x45, 0x39],
+    [0x3c, 0x4a, 0x49, 0x49, 0x30],
+    [0x01, 0x71, 0x09, 0x05, 0x03],
+    [0x36, 0x49, 0x49, 0x49, 0x36],
+    [0x06, 0x49, 0x49, 0x29, 0x1e],
+    [0x00, 0x36, 0x36, 0x00, 0x00],
+    [0x00, 0x56, 0x36, 0x00, 0x00],
+    [0x08, 0x14, 0x22, 0x41, 0x00],
+    [0x14, 0x14, 0x14, 0x14, 0x14],
+    [0x00, 0x41, 0x22, 0x14, 0x08],
+    [0x02, 0x01, 0x51, 0x09, 0x06],
+    [0x32, 0x49, 0x79, 0x41, 0x3e],
+    [0x7e, 0x11, 0x11, 0x11, 0x7e],
+    [0x7f, 0x49, 0x49, 0x49, 0x36],
+    [0x3e, 0x41, 0x41, 0x41, 0x22],
+    [0x7f, 0x41, 0x41, 0x22, 0x1c],
+    [0x7f, 0x49, 0x49, 0x49, 0x41],
+    [0x7f, 0x09, 0x09, 0x09, 0x01],
+    [0x3e, 0x41, 0x49, 0x49, 0x7a],
+    [0x7f, 0x08, 0x08, 0x08, 0x7f],
+    [0x00, 0x41, 0x7f, 0x41, 0x00],
+    [0x20, 0x40, 0x41, 0x3f, 0x01],
+    [0x7f, 0x08, 0x14, 0x22, 0x41],
+    [0x7f, 0x40, 0x40, 0x40, 0x40],
+    [0x7f, 0x02, 0x0c, 0x02, 0x7f],
+    [0x7f, 0x04, 0x08, 0x10, 0x7f],
+    [0x3e, 0x41, 0x41, 0x41, 0x3e],
+    [0x7f, 0x09, 0x09, 0x09, 0x06],
+    [0x3e, 0x41, 0x51, 0x21, 0x5e],
+    [0x7f, 0x09, 0x19, 0x29, 0x46],
+    [0x46, 0x49, 0x49, 0x49, 0x31],
+    [0x01, 0x01, 0x7f, 0x01, 0x01],
+    [0x3f, 0x40, 0x40, 0x40, 0x3f],
+    [0x1f, 0x20, 0x40, 0x20, 0x1f],
+    [0x3f, 0x40, 0x38, 0x40, 0x3f],
+    [0x63, 0x14, 0x08, 0x14, 0x63],
+    [0x07, 0x08, 0x70, 0x08, 0x07],
+    [0x61, 0x51, 0x49, 0x45, 0x43],
+    [0x00, 0x7f, 0x41, 0x41, 0x00],
+    [0x02, 0x04, 0x08, 0x10, 0x20],
+    [0x00, 0x41, 0x41, 0x7f, 0x00],
+    [0x04, 0x02, 0x01, 0x02, 0x04],
+    [0x40, 0x40, 0x40, 0x40, 0x40],
+    [0x00, 0x01, 0x02, 0x04, 0x00],
+    [0x20, 0x54, 0x54, 0x54, 0x78],
+    [0x7f, 0x48, 0x44, 0x44, 0x38],
+    [0x38, 0x44, 0x44, 0x44, 0x20],
+    [0x38, 0x44, 0x44, 0x48, 0x7f],
+    [0x38, 0x54, 0x54, 0x54, 0x18],
+    [0x08, 0x7e, 0x09, 0x01, 0x02],
+    [0x0c, 0x52, 0x52, 0x52, 0x3e],
+    [0x7f, 0x08, 0x04, 0x04, 0x78],
+    [0x00, 0x44, 0x7d, 0x40, 0x00],
+    [0x20, 0x40, 0x44, 0x3d, 0x00],
+    [0x7f, 0x10, 0x28, 0x44, 0x00],
+    [0x00, 0x41, 0x7f, 0x40, 0x00],
+    [0x7c, 0x04, 0x18, 0x04, 0x78],
+    [0x7c, 0x08, 0x04, 0x04, 0x78],
+    [0x38, 0x44, 0x44, 0x44, 0x38],
+    [0x7c, 0x14, 0x14, 0x14, 0x08],
+    [0x08, 0x14, 0x14, 0x18, 0x7c],
+    [0x7c, 0x08, 0x04, 0x04, 0x08],
+    [0x48, 0x54, 0x54, 0x54, 0x20],
+    [0x04, 0x3f, 0x44, 0x40, 0x20],
+    [0x3c, 0x40, 0x40, 0x20, 0x7c],
+    [0x1c, 0x20, 0x40, 0x20, 0x1c],
+    [0x3c, 0x40, 0x30, 0x40, 0x3c],
+    [0x44, 0x28, 0x10, 0x28, 0x44],
+    [0x0c, 0x50, 0x50, 0x50, 0x3c],
+    [0x44, 0x64, 0x54, 0x4c, 0x44],
+    [0x00, 0x08, 0x36, 0x41, 0x00],
+    [0x00, 0x00, 0x7f, 0x00, 0x00],
+    [0x00, 0x41, 0x36, 0x08, 0x00],
+    [0x08, 0x04, 0x08, 0x10, 0x08],
+];
+
+/// Renders `data` as a barcode that fills the printable width of `media`.
+///
+/// Die-cut labels are filled exactly, the barcode is centered on the label. On continuous
+/// media, QR codes are printed on a square page and Code 128 barcodes are a third as long as the
+/// media is wide.
+///
+/// If `human_readable` is set, `data` is additionally printed as text below the barcode using a
+/// small built-in font. Characters outside of printable ASCII are shown as `?`. On continuous
+/// media, the text is added to the length of the page, on die-cut labels the barcode is shortened
+/// accordingly.
+///
+/// Returns an error if the data cannot be encoded or the barcode or text does not fit the media.
+pub fn render(
+    data: &str,
+    symbology: Symbology,
+    media: Media,
+    human_readable: bool,
+) -> Result<DynamicImage, BQLError> {
+    let media_settings = MediaSettings::new(&media)?;
+    let width = media_settings.width_dots;
+    let text_scale = if human_readable {
+        text_scale(data, width)?
+    } else {
+        0
+    };
+    let text_height = text_scale * TEXT_LINE_HEIGHT;
+    let length = |continuous_length: u32| match media_settings.media_type {
+        MediaType::Continuous => Some(continuous_length),
+        MediaType::DieCut { length_dots, .. } => length_dots.checked_sub(text_height),
+    };
+    let img = match symbology {
+        Symbology::Code128 => {
+            let modules = code128_modules(data)?;
+            let length = length(width / 3).ok_or(BQLError::InvalidBarcodeData)?;
+            let module_size = width / (modules.len() as u32 + 2 * CODE128_QUIET_ZONE);
+            if module_size == 0 {
+                return Err(BQLError::InvalidBarcodeData);
+            }
+            let offset = (width - module_size * modules.len() as u32) / 2;
+            GrayImage::from_fn(width, length, |x, _| {
+                let dark = x
+                    .checked_sub(offset)
+                    .and_then(|x| modules.get((x / module_size) as usize))
+                    .is_some_and(|&dark| dark);
+                Luma([if dark { 0 } else { 255 }])
+            })
+        }
+        Symbology::Qr => {
+            let code = QrCode::new(data).map_err(|_| BQLError::InvalidBarcodeData)?;
+            let colors = code.to_colors();
+            let modules = code.width() as u32;
+            let length = length(width).ok_or(BQLError::InvalidBarcodeData)?;
+            let module_size = width.min(length) / (modules + 2 * QR_QUIET_ZONE);
+            if module_size == 0 {
+                return Err(BQLError::InvalidBarcodeData);
+            }
+            let offset_x = (width - module_size * modules) / 2;
+            let offset_y = (length - module_size * modules) / 2;
+            GrayImage::from_fn(width, length, |x, y| {
+                let (Some(x), Some(y)) = (x.checked_sub(offset_x), y.checked_sub(offset_y)) else {
+                    return Luma([255]);
+                };
+                let (x, y) = (x / module_size, y / module_size);
+                let dark =
+                    x < modules && y < modules && colors[(y * modules + x) as usize] == Color::Dark;
+                Luma([if dark { 0 } else { 255 }])
+            })
+        }
+    };
+    if !human_readable {
+        return Ok(DynamicImage::ImageLuma8(img));
+    }
+    let mut labeled = GrayImage::from_pixel(width, img.height() + text_height, Luma([255]));
+    imageops::replace(&mut labeled, &img, 0, 0);
+    draw_text(&mut labeled, data, img.height(), text_scale);
+    Ok(DynamicImage::ImageLuma8(labeled))
+}
+
+/// Returns the largest scale of the built-in font, up to [MAX_TEXT_SCALE], at which `text` fits
+/// into the given width.
+fn text_scale(text: &str, width: u32) -> Result<u32, BQLError> {
+    let advance = text.chars().count() as u32 * GLYPH_ADVANCE;
+    match width.checked_div(advance).unwrap_or(0) {
+        0 => Err(BQLError::InvalidBarcodeData),
+        scale => Ok(scale.min(MAX_TEXT_SCALE)),
+    }
+}
+
+/// Draws `text` horizontally centered into the line of text starting at row `top`.
+fn draw_text(img: &mut GrayImage, text: &str, top: u32, scale: u32) {
+    let text_width = text.chars().count() as u32 * GLYPH_ADVANCE * scale;
+    let left = (img.width() - text_width) / 2;
+    let top = top + TEXT_PADDING * scale;
+    text.chars().enumerate().for_each(|(i, c)| {
+        let glyph = match c {
+            ' '..='~' => FONT_5X7[c as usize - 0x20],
+            _ => FONT_5X7[usize::from(b'?' - 0x20)],
+        };
+        let glyph_left = left + i as u32 * GLYPH_ADVANCE * scale;
+        for (column, bits) in glyph.into_iter().enumerate() {
+            for row in (0..7).filter(|row| bits & (1 << row) != 0) {
+                for dx in 0..scale {
+                    for dy in 0..scale {
+                        let x = glyph_left + column as u32 * scale + dx;
+                        img.put_pixel(x, top + row * scale + dy, Luma([0]));
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Encodes the data using code set B and returns whether each module is a bar.
+fn code128_modules(data: &str) -> Result<Vec<bool>, BQLError> {
+    let values = data
+        .chars()
+        .map(|c| match c {
+            ' '..='\x7f' => Ok(c as usize - 0x20),
+            _ => Err(BQLError::InvalidBarcodeData),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if values.is_empty() {
+        return Err(BQLError::InvalidBarcodeData);
+    }
+    let checksum = values
+        .iter()
+        .enumerate()
+        .fold(CODE128_START_B, |sum, (i, value)| sum + (i + 1) * value)
+        % 103;
+    let symbols = std::iter::once(CODE128_START_B)
+        .chain(values)
+        .chain(std::iter::once(checksum))
+        .map(|value| &CODE128_PATTERNS[value][..])
+        .chain(std::iter::once(&CODE128_STOP[..]));
+    Ok(symbols
+        .flat_map(|widths| {
+            widths
+                .iter()
+                .enumerate()
+                .flat_map(|(i, width)| std::iter::repeat_n(i % 2 == 0, usize::from(width - b'0')))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::printjob::PrintJob;
+
+    fn dark_pixels(img: &DynamicImage) -> usize {
+        img.to_luma8().pixels().filter(|px| px.0[0] == 0).count()
+    }
+
+    #[test]
+    fn barcodes_match_media_width() {
+        for media in [Media::C62, Media::D24] {
+            let width_dots = media.settings().unwrap().width_dots;
+            for symbology in [Symbology::Code128, Symbology::Qr] {
+                for human_readable in [false, true] {
+                    let img = render("ABC-123", symbology, media, human_readable).unwrap();
+                    assert_eq!(img.width(), width_dots);
+                    assert!(dark_pixels(&img) > 0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn human_readable_text_is_printed_below_barcode() {
+        let plain = render("ABC-123", Symbology::Code128, Media::C62, false).unwrap();
+        let labeled = render("ABC-123", Symbology::Code128, Media::C62, true).unwrap();
+        assert!(labeled.height() > plain.height());
+        let text = labeled.crop_imm(0, plain.height(), labeled.width(), labeled.height());
+        assert!(dark_pixels(&text) > 0);
+    }
+
+    #[test]
+    fn text_too_wide_for_media_is_rejected() {
+        let data = "0123456789".repeat(5);
+        assert!(render(&data, Symbology::Qr, Media::D24, false).is_ok());
+        assert_eq!(
+            render(&data, Symbology::Qr, Media::D24, true),
+            Err(BQLError::InvalidBarcodeData)
+        );
+    }
+
+    #[test]
+    fn qr_code_on_round_label_is_not_masked() {
+        let job =
+            PrintJob::barcode("https://example.com", Symbology::Qr, Media::D24, false).unwrap();
+        let (black_dots, _) = job.raster_image().unwrap().dot_counts();
+        assert_eq!(black_dots, dark_pixels(&job.image));
+    }
+}
@@ -1,13 +1,36 @@
-use crate::media::{MediaSettings, MediaType};
+//! Low-level helpers for working with compiled command data
+use std::{
+    fmt,
+    io::{self, Write},
+};
 
-pub(crate) enum DynamicCommandMode {
-    // EscP,
+#[cfg(feature = "serde")]
+use serde::Deserialize;
+
+use crate::{
+    error::ParseError,
+    media::{MediaSettings, MediaType},
+};
+
+/// This enum specifies the command modes the printer can be switched to.
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
+pub enum DynamicCommandMode {
+    /// ESC/P mode for printing text with the printer's fonts, see
+    /// [EscpJob][crate::escp::EscpJob]
+    EscP,
+    /// Raster mode for printing images, used by [PrintJob][crate::printjob::PrintJob]
     Raster,
-    // PTouchTemplate,
+    /// P-touch Template mode for printing templates stored on the printer
+    PTouchTemplate,
 }
 
-pub(crate) enum ColorPower {
+/// This enum specifies the thermal energy used for printing a layer of a two-color print job.
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+pub enum ColorPower {
+    /// Low energy, used for the red layer by default
     LowEnergy,
+    /// High energy, used for the black layer by default
     HighEnergy,
 }
 
@@ -30,7 +53,10 @@ pub(crate) enum RasterCommand {
         data: Vec<u8>,
         color_power: ColorPower,
     },
-    // ZeroRasterGraphics,
+    ZeroRasterGraphics,
+    RawData {
+        data: Vec<u8>,
+    },
     Print,
     PrintWithFeed,
     SelectCompressionMode {
@@ -56,6 +82,60 @@ pub(crate) enum RasterCommand {
     },
 }
 
+impl fmt::Display for RasterCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use RasterCommand::*;
+        match self {
+            Initialize => write!(f, "Initialize"),
+            Invalidate => write!(f, "Invalidate"),
+            SpecifyMarginAmount { margin_size } => write!(f, "SpecifyMarginAmount({margin_size})"),
+            SwitchDynamicCommandMode { command_mode } => {
+                write!(f, "SwitchDynamicCommandMode({command_mode:?})")
+            }
+            SwitchAutomaticStatusNotificationMode { notify } => {
+                write!(f, "SwitchAutomaticStatusNotificationMode(notify: {notify})")
+            }
+            RasterGraphicsTransfer { data } => {
+                write!(f, "RasterGraphicsTransfer({} bytes)", data.len())
+            }
+            TwoColorRasterGraphicsTransfer { data, color_power } => write!(
+                f,
+                "TwoColorRasterGraphicsTransfer({color_power:?}, {} bytes)",
+                data.len()
+            ),
+            ZeroRasterGraphics => write!(f, "ZeroRasterGraphics"),
+            RawData { data } => write!(f, "RawData({} bytes)", data.len()),
+            Print => write!(f, "Print"),
+            PrintWithFeed => write!(f, "PrintWithFeed"),
+            SelectCompressionMode { tiff_compression } => {
+                write!(f, "SelectCompressionMode(tiff: {tiff_compression})")
+            }
+            SpecifyPageNumber { cut_every } => write!(f, "SpecifyPageNumber({cut_every})"),
+            VariousMode { auto_cut } => write!(f, "VariousMode(auto_cut: {auto_cut})"),
+            ExpandedMode {
+                two_color,
+                cut_at_end,
+                high_dpi,
+            } => write!(
+                f,
+                "ExpandedMode(two_color: {two_color}, cut_at_end: {cut_at_end}, high_dpi: {high_dpi})"
+            ),
+            PrintInformation {
+                media_settings,
+                quality_priority,
+                recovery_on,
+                no_lines,
+                first_page,
+            } => write!(
+                f,
+                "PrintInformation(media_type: {:?}, width_mm: {}, quality_priority: {quality_priority}, \
+                 recovery_on: {recovery_on}, no_lines: {no_lines}, first_page: {first_page})",
+                media_settings.media_type, media_settings.width_mm
+            ),
+        }
+    }
+}
+
 impl From<RasterCommand> for Vec<u8> {
     fn from(value: RasterCommand) -> Self {
         use RasterCommand::*;
@@ -73,9 +153,9 @@ impl From<RasterCommand> for Vec<u8> {
             SwitchDynamicCommandMode { command_mode } => {
                 use DynamicCommandMode::*;
                 let m = match command_mode {
-                    // EscP => 0x00,
+                    EscP => 0x00,
                     Raster => 0x01,
-                    // PTouchTemplate => 0x03,
+                    PTouchTemplate => 0x03,
                 };
                 vec![0x1b, 0x69, 0x61, m]
             }
@@ -100,9 +180,10 @@ impl From<RasterCommand> for Vec<u8> {
                 res.append(&mut data);
                 res
             }
-            // ZeroRasterGraphics => {
-            //     vec![0x5a]
-            // }
+            ZeroRasterGraphics => {
+                vec![0x5a]
+            }
+            RawData { data } => data,
             Print => {
                 vec![0x0c]
             }
@@ -192,12 +273,216 @@ pub(crate) struct CommandBuilder {
     commands: Vec<Vec<u8>>,
 }
 
-impl CommandBuilder {
-    pub fn add(&mut self, cmd: RasterCommand) {
+pub(crate) trait CommandSink {
+    fn add(&mut self, cmd: RasterCommand);
+}
+
+impl CommandSink for CommandBuilder {
+    fn add(&mut self, cmd: RasterCommand) {
         self.commands.push(cmd.into())
     }
+}
+
+impl CommandBuilder {
+    pub fn len(&self) -> usize {
+        self.commands.iter().map(Vec::len).sum()
+    }
 
     pub fn build(self) -> Vec<u8> {
         self.commands.concat()
     }
 }
+
+/// Collects a human-readable description of every added command.
+#[derive(Default)]
+pub(crate) struct CommandDescriber {
+    descriptions: Vec<String>,
+}
+
+impl CommandSink for CommandDescriber {
+    fn add(&mut self, cmd: RasterCommand) {
+        self.descriptions.push(cmd.to_string())
+    }
+}
+
+impl CommandDescriber {
+    pub fn build(self) -> Vec<String> {
+        self.descriptions
+    }
+}
+
+/// Collects the commands of every page separately. The invalidate and initialize commands before
+/// the first page are collected as preamble.
+#[derive(Default)]
+pub(crate) struct PageSplitter {
+    preamble: Vec<u8>,
+    pages: Vec<Vec<u8>>,
+    current_page: Vec<u8>,
+}
+
+impl CommandSink for PageSplitter {
+    fn add(&mut self, cmd: RasterCommand) {
+        use RasterCommand::*;
+        match cmd {
+            Invalidate | Initialize if self.pages.is_empty() && self.current_page.is_empty() => {
+                self.preamble.append(&mut cmd.into())
+            }
+            Print | PrintWithFeed => {
+                self.current_page.append(&mut cmd.into());
+                self.pages.push(std::mem::take(&mut self.current_page));
+            }
+            _ => self.current_page.append(&mut cmd.into()),
+        }
+    }
+}
+
+impl PageSplitter {
+    pub fn build(self) -> (Vec<u8>, Vec<Vec<u8>>) {
+        (self.preamble, self.pages)
+    }
+}
+
+/// Writes commands to a writer as they are added. After the first error, all further commands
+/// are discarded.
+pub(crate) struct CommandWriter<W> {
+    writer: W,
+    result: io::Result<()>,
+}
+
+impl<W: Write> CommandWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            result: Ok(()),
+        }
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        self.result
+    }
+}
+
+impl<W: Write> CommandSink for CommandWriter<W> {
+    fn add(&mut self, cmd: RasterCommand) {
+        if self.result.is_ok() {
+            self.result = self.writer.write_all(&Vec::from(cmd));
+        }
+    }
+}
+
+/// Creates command data that resets the printer, switches it to the given command mode and then
+/// sends `payload` unchanged.
+///
+/// This is a low-level building block for modes without dedicated support in this crate. The
+/// payload has to contain all further commands of the mode, including the final print command.
+pub fn mode_payload(command_mode: DynamicCommandMode, payload: &[u8]) -> Vec<u8> {
+    let mut commands = CommandBuilder::default();
+
+    use RasterCommand::*;
+    commands.add(Invalidate);
+    commands.add(Initialize);
+    commands.add(SwitchDynamicCommandMode { command_mode });
+    commands.add(RawData {
+        data: payload.to_vec(),
+    });
+    commands.build()
+}
+
+/// Validates the structure of compiled command data, e.g. a `.bin` file created by
+/// [PrintJob::compile][crate::printjob::PrintJob::compile] that is about to be sent to a printer.
+///
+/// The data has to start with the initialize command, optionally preceded by the invalidate
+/// command, must only contain well-formed commands and has to end with a print command.
+/// Once the data switches to a command mode other than raster, only the final print command is
+/// checked.
+pub fn validate_stream(bytes: &[u8]) -> Result<(), ParseError> {
+    let preamble_len = bytes.iter().take_while(|&&byte| byte == 0x00).count();
+    if !bytes[preamble_len..].starts_with(&[0x1b, 0x40]) {
+        return Err(ParseError::MissingPreamble);
+    }
+    let mut offset = preamble_len;
+    let mut last_command = None;
+    while offset < bytes.len() {
+        let rest = &bytes[offset..];
+        let len = match rest {
+            [0x00, ..] => rest.iter().take_while(|&&byte| byte == 0x00).count(),
+            [0x1b, 0x40, ..] | [0x4d, ..] => 2,
+            [0x1b, 0x69, 0x61, mode, ..] if *mode != 0x01 => {
+                last_command = bytes.last().copied();
+                break;
+            }
+            [0x1b, 0x69, 0x21 | 0x41 | 0x4b | 0x4d | 0x61, ..] => 4,
+            [0x1b, 0x69, 0x64, ..] => 5,
+            [0x1b, 0x69, 0x7a, ..] => 13,
+            [0x1b] | [0x1b, 0x69] | [0x67 | 0x77] | [0x67 | 0x77, _] => {
+                return Err(ParseError::Truncated(offset))
+            }
+            [0x67 | 0x77, _, n, ..] => 3 + usize::from(*n),
+            [0x5a | 0x0c | 0x1a, ..] => 1,
+            _ => return Err(ParseError::UnknownCommand(offset)),
+        };
+        if len > rest.len() {
+            return Err(ParseError::Truncated(offset));
+        }
+        last_command = Some(rest[0]);
+        offset += len;
+    }
+    match last_command {
+        Some(0x0c | 0x1a) => Ok(()),
+        _ => Err(ParseError::MissingPrint),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, GrayImage};
+
+    use crate::{escp::EscpJob, media::Media, printjob::PrintJob};
+
+    fn compiled(media: Media) -> Vec<u8> {
+        let img = DynamicImage::ImageLuma8(GrayImage::new(696, 20));
+        PrintJob::new(img, media).compile().unwrap()
+    }
+
+    #[test]
+    fn compiled_data_is_valid() {
+        assert_eq!(validate_stream(&compiled(Media::C62)), Ok(()));
+        assert_eq!(validate_stream(&compiled(Media::C62R)), Ok(()));
+        let escp = EscpJob {
+            text: "Hello".to_string(),
+        };
+        assert_eq!(validate_stream(&escp.compile()), Ok(()));
+    }
+
+    #[test]
+    fn truncated_data_is_invalid() {
+        let data = compiled(Media::C62);
+        assert!(matches!(
+            validate_stream(&data[..data.len() - 50]),
+            Err(ParseError::Truncated(_))
+        ));
+        assert_eq!(
+            validate_stream(&data[..data.len() - 1]),
+            Err(ParseError::MissingPrint)
+        );
+        assert_eq!(
+            validate_stream(b"not a print job"),
+            Err(ParseError::MissingPreamble)
+        );
+    }
+
+    #[test]
+    fn mode_payload_emits_mode_byte() {
+        for (command_mode, mode_byte) in [
+            (DynamicCommandMode::EscP, 0x00),
+            (DynamicCommandMode::Raster, 0x01),
+            (DynamicCommandMode::PTouchTemplate, 0x03),
+        ] {
+            let data = mode_payload(command_mode, b"payload");
+            let expected = [&[0x1b, 0x40, 0x1b, 0x69, 0x61, mode_byte][..], b"payload"].concat();
+            assert!(data.ends_with(&expected));
+            assert!(data[..data.len() - expected.len()].iter().all(|&b| b == 0));
+        }
+    }
+}
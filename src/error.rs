@@ -7,4 +7,62 @@ pub enum BQLError {
     /// Returned when the provided image has a size incompatible with the provided media type.
     #[error("media and image are not compatible")]
     DimensionMismatch,
+    /// Returned when red printing is requested on media without two-color support.
+    #[error("media does not support two-color printing")]
+    TwoColorUnsupported,
+    /// Returned when the provided media is not supported yet.
+    #[error("media is not supported yet")]
+    UnsupportedMedia,
+    /// Returned when parsing a [Media][crate::media::Media] from an unknown name.
+    #[error("unknown media")]
+    UnknownMedia,
+    /// Returned when [CutEvery][crate::printjob::CutBehavior::CutEvery] is used with an interval of
+    /// zero pages.
+    #[error("cut interval must not be zero")]
+    ZeroCutInterval,
+    /// Returned when a print job with zero [pages][crate::printjob::PrintJob::no_pages] is
+    /// compiled.
+    #[error("print job must have at least one page")]
+    ZeroPages,
+    /// Returned when an image is split into segments of zero lines, see
+    /// [from_image_segmented][crate::printjob::PrintJob::from_image_segmented].
+    #[error("segment length must not be zero")]
+    ZeroSegmentLength,
+    /// Returned when the [feed_margin][crate::printjob::PrintJob::feed_margin] is outside the
+    /// range supported by the printer.
+    #[error("feed margin is out of range")]
+    InvalidFeedMargin,
+    /// Returned when the [gamma][crate::printjob::PrintJob::gamma] correction is not a positive,
+    /// finite number.
+    #[error("gamma must be positive")]
+    InvalidGamma,
+    /// Returned when the [brightness][crate::printjob::PrintJob::brightness] adjustment is outside
+    /// of -255 to 255.
+    #[error("brightness is out of range")]
+    InvalidBrightness,
+    /// Returned when the [contrast][crate::printjob::PrintJob::contrast] adjustment is not a finite
+    /// number.
+    #[error("contrast must be finite")]
+    InvalidContrast,
+    /// Returned when the data cannot be encoded as the requested barcode or the barcode does not
+    /// fit the media.
+    #[error("data cannot be printed as barcode")]
+    InvalidBarcodeData,
+}
+
+/// The error type for validating compiled command data
+#[derive(Error, Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum ParseError {
+    /// Returned when the data does not start with the initialize command.
+    #[error("data does not start with a valid preamble")]
+    MissingPreamble,
+    /// Returned when an unknown command is found at the given offset.
+    #[error("unknown command at offset {0}")]
+    UnknownCommand(usize),
+    /// Returned when the command at the given offset is cut off.
+    #[error("truncated command at offset {0}")]
+    Truncated(usize),
+    /// Returned when the data does not end with a print command.
+    #[error("data does not end with a print command")]
+    MissingPrint,
 }
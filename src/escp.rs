@@ -0,0 +1,59 @@
+//! Minimal support for printing plain text in the ESC/P command mode
+use crate::commands::{CommandBuilder, CommandSink, DynamicCommandMode, RasterCommand};
+
+/// This struct defines a print job which prints plain text using the printer's ESC/P command mode
+/// instead of raster graphics.
+///
+/// The text is printed with the printer's default font settings. Characters outside of ASCII are
+/// replaced by `?`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct EscpJob {
+    /// The text to print
+    pub text: String,
+}
+
+impl EscpJob {
+    /// Create the compiled command data for this job.
+    ///
+    /// Like [PrintJob::compile][crate::printjob::PrintJob::compile], the result can be directly
+    /// sent to your printer.
+    pub fn compile(self) -> Vec<u8> {
+        let mut commands = CommandBuilder::default();
+
+        use RasterCommand::*;
+        commands.add(Invalidate);
+        commands.add(Initialize);
+        commands.add(SwitchDynamicCommandMode {
+            command_mode: DynamicCommandMode::EscP,
+        });
+        commands.add(RawData {
+            data: self
+                .text
+                .chars()
+                .map(|c| if c.is_ascii() { c as u8 } else { b'?' })
+                .collect(),
+        });
+        commands.add(Print);
+        commands.build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escp_job_switches_to_escp_mode() {
+        let data = EscpJob {
+            text: "Hello".to_string(),
+        }
+        .compile();
+        let mode_switch = [0x1b, 0x69, 0x61, 0x00];
+        let position = data
+            .windows(4)
+            .position(|window| window == mode_switch)
+            .unwrap();
+        assert!(data[position + 4..].starts_with(b"Hello"));
+        assert_eq!(data.last(), Some(&0x0c));
+    }
+}
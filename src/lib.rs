@@ -22,12 +22,11 @@
 //!     let img = image::open("test.png")?;
 //!     let job = PrintJob {
 //!         no_pages: 1,
-//!         image: img,
-//!         media: Media::C62,       // use 62mm wide continuous tape
 //!         high_dpi: false,
 //!         compressed: false,       // unsupported
 //!         quality_priority: false, // no effect on two-color printing
 //!         cut_behaviour: CutBehavior::CutAtEnd,
+//!         ..PrintJob::new(img, Media::C62) // use 62mm wide continuous tape
 //!     };
 //!     let data = job.compile()?;
 //!     let mut file = File::create("test.bin")?;
@@ -38,8 +37,11 @@
 //!
 //! ```
 #![warn(missing_docs)]
-mod commands;
+#[cfg(feature = "barcode")]
+pub mod barcode;
+pub mod commands;
 pub mod error;
+pub mod escp;
 pub mod media;
 pub mod printjob;
-mod raster_image;
+pub mod raster_image;
@@ -1,19 +1,42 @@
 //! Definitions for the available paper media types
+use std::str::FromStr;
+
 #[cfg(feature = "serde")]
 use serde::Deserialize;
 
+use crate::error::BQLError;
+
+/// The amount of dots in a single raster line, i.e. the width of the print head.
+pub(crate) const RASTER_LINE_DOTS: u32 = 720;
+/// The amount of bytes a single raster line is packed into.
+pub(crate) const RASTER_LINE_BYTES: usize = RASTER_LINE_DOTS.div_ceil(8) as usize;
+
+/// Returns the amount of bytes of a single raster line when printing on the given media.
+///
+/// Each byte packs eight dots, so this is the raster line width in dots divided by eight, rounded
+/// up. All currently supported media share the 720 dots wide print head, i.e. 90 bytes.
+pub fn raster_line_bytes(_media: Media) -> usize {
+    RASTER_LINE_BYTES
+}
+
+/// This struct describes the geometry and capabilities of a media, see [Media::settings].
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
-pub(crate) struct MediaSettings {
+pub struct MediaSettings {
+    /// Whether the media is continuous or die-cut, including the label length
     pub media_type: MediaType,
+    /// The printable width in dots, i.e. the width an image must have
     pub width_dots: u32,
+    /// The width of the media in millimeters
     pub width_mm: u8,
+    /// The amount of blank dots to the left of the printable area within a raster line
     pub left_margin: u32,
+    /// Whether the media supports two-color printing
     pub color: bool,
 }
 
 impl MediaSettings {
-    pub fn new(media: &Media) -> Self {
-        match media {
+    pub(crate) fn new(media: &Media) -> Result<Self, BQLError> {
+        Ok(match media {
             Media::C62 => Self {
                 media_type: MediaType::Continuous,
                 width_dots: 696,
@@ -38,18 +61,145 @@ impl MediaSettings {
                 left_margin: 442,
                 color: false,
             },
-            _ => todo!(),
+            _ => return Err(BQLError::UnsupportedMedia),
+        })
+    }
+}
+
+const ALL_MEDIA: [Media; 24] = [
+    Media::C12,
+    Media::C29,
+    Media::C38,
+    Media::C50,
+    Media::C54,
+    Media::C62,
+    Media::C62R,
+    Media::D17x54,
+    Media::D17x87,
+    Media::D23x23,
+    Media::D29x42,
+    Media::D29x90,
+    Media::D38x90,
+    Media::D39x48,
+    Media::D52x29,
+    Media::D54x29,
+    Media::D60x86,
+    Media::D62x29,
+    Media::D62x60,
+    Media::D62x75,
+    Media::D62x100,
+    Media::D12,
+    Media::D24,
+    Media::D58,
+];
+
+impl Media {
+    /// Returns all media an image with the given dimensions can be printed on.
+    ///
+    /// Continuous media only has to match the width, die-cut media has to match both width and
+    /// length. Only supported media is considered.
+    pub fn candidates_for(width: u32, height: u32) -> Vec<Media> {
+        ALL_MEDIA
+            .into_iter()
+            .filter(|media| {
+                MediaSettings::new(media).is_ok_and(|settings| {
+                    settings.width_dots == width
+                        && match settings.media_type {
+                            MediaType::Continuous => true,
+                            MediaType::DieCut { length_dots, .. } => length_dots == height,
+                        }
+                })
+            })
+            .collect()
+    }
+
+    /// Looks up the supported media with the given physical dimensions.
+    ///
+    /// Continuous media is only found if `length_mm` is [None]. If several media share the same
+    /// dimensions, e.g. [C62][Media::C62] and [C62R][Media::C62R], the monochrome one is returned.
+    pub fn from_dimensions(
+        width_mm: u8,
+        length_mm: Option<u8>,
+        label_type: LabelType,
+    ) -> Option<Media> {
+        ALL_MEDIA.into_iter().find(|media| {
+            MediaSettings::new(media).is_ok_and(|settings| {
+                settings.width_mm == width_mm
+                    && match (settings.media_type, label_type) {
+                        (MediaType::Continuous, LabelType::Continuous) => length_mm.is_none(),
+                        (MediaType::DieCut { length_mm: len, .. }, LabelType::DieCut) => {
+                            length_mm == Some(len)
+                        }
+                        _ => false,
+                    }
+            })
+        })
+    }
+
+    /// Returns the geometry and capabilities of this media.
+    ///
+    /// Returns an error if the media is not supported yet.
+    ///
+    /// ```
+    /// use brother_ql::media::Media;
+    ///
+    /// let settings = Media::C62.settings().unwrap();
+    /// assert_eq!(settings.width_dots, 696);
+    /// ```
+    pub fn settings(self) -> Result<MediaSettings, BQLError> {
+        MediaSettings::new(&self)
+    }
+
+    /// Returns whether the labels of this media are round.
+    pub(crate) fn is_round(self) -> bool {
+        matches!(self, Media::D12 | Media::D24 | Media::D58)
+    }
+
+    /// Returns the media to use when printing only a single color on this media.
+    pub(crate) fn monochrome(self) -> Self {
+        match self {
+            Media::C62R => Media::C62,
+            media => media,
         }
     }
 }
 
+impl FromStr for Media {
+    type Err = BQLError;
+
+    /// Parses the name of a media variant, e.g. `"C62"` or `"d24"`, ignoring case.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ALL_MEDIA
+            .into_iter()
+            .find(|media| format!("{media:?}").eq_ignore_ascii_case(s))
+            .ok_or(BQLError::UnknownMedia)
+    }
+}
+
 /// This enum represents the basic two media types:
 /// * continuous label rolls
 /// * die-cut labels
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
-pub(crate) enum MediaType {
+pub enum MediaType {
+    /// Continuous label roll, the label length is determined by the image
+    Continuous,
+    /// Die-cut labels of a fixed length
+    DieCut {
+        /// The printable length of a label in dots, i.e. the height an image must have
+        length_dots: u32,
+        /// The length of a label in millimeters
+        length_mm: u8,
+    },
+}
+
+/// This enum represents the type of labels on a roll.
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum LabelType {
+    /// Continuous label roll
     Continuous,
-    DieCut { length_dots: u32, length_mm: u8 },
+    /// Die-cut labels
+    DieCut,
 }
 
 /// This enum represents the available paper types.
@@ -108,3 +258,85 @@ pub enum Media {
     /// Die-cut 58mm circle labels
     D58,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raster_lines_are_90_bytes() {
+        for media in ALL_MEDIA {
+            assert_eq!(raster_line_bytes(media), 90);
+        }
+        let img = image::DynamicImage::new_luma8(696, 10);
+        let raster_image = crate::raster_image::RasterImage::from_image(img, Media::C62).unwrap();
+        assert!(raster_image
+            .black_layer()
+            .iter()
+            .all(|line| line.len() == raster_line_bytes(Media::C62)));
+    }
+
+    #[test]
+    fn candidates_match_width_and_die_cut_length() {
+        // D62x29 would match as well, but is not supported yet
+        assert_eq!(Media::candidates_for(696, 318), [Media::C62, Media::C62R]);
+        assert_eq!(Media::candidates_for(236, 236), [Media::D24]);
+        assert_eq!(Media::candidates_for(236, 100), []);
+    }
+
+    #[test]
+    fn media_is_found_by_dimensions() {
+        assert_eq!(
+            Media::from_dimensions(62, None, LabelType::Continuous),
+            Some(Media::C62)
+        );
+        assert_eq!(
+            Media::from_dimensions(24, Some(24), LabelType::DieCut),
+            Some(Media::D24)
+        );
+        assert_eq!(
+            Media::from_dimensions(62, Some(29), LabelType::Continuous),
+            None
+        );
+        assert_eq!(Media::from_dimensions(24, None, LabelType::DieCut), None);
+        assert_eq!(
+            Media::from_dimensions(15, None, LabelType::Continuous),
+            None
+        );
+        // D62x29 has no media settings yet
+        assert_eq!(
+            Media::from_dimensions(62, Some(29), LabelType::DieCut),
+            None
+        );
+    }
+
+    #[test]
+    fn settings_expose_media_geometry() {
+        let c62 = Media::C62.settings().unwrap();
+        assert_eq!(c62.media_type, MediaType::Continuous);
+        assert_eq!(
+            (c62.width_dots, c62.width_mm, c62.left_margin),
+            (696, 62, 12)
+        );
+        assert!(!c62.color);
+        assert!(Media::C62R.settings().unwrap().color);
+        let d24 = Media::D24.settings().unwrap();
+        assert_eq!(
+            d24.media_type,
+            MediaType::DieCut {
+                length_dots: 236,
+                length_mm: 24
+            }
+        );
+        assert_eq!(Media::C29.settings(), Err(BQLError::UnsupportedMedia));
+    }
+
+    #[test]
+    fn media_is_parsed_ignoring_case() {
+        assert_eq!("C62".parse(), Ok(Media::C62));
+        assert_eq!("c62r".parse(), Ok(Media::C62R));
+        assert_eq!("D24".parse(), Ok(Media::D24));
+        assert_eq!("C63".parse::<Media>(), Err(BQLError::UnknownMedia));
+        assert_eq!("".parse::<Media>(), Err(BQLError::UnknownMedia));
+    }
+}
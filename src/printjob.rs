@@ -1,16 +1,52 @@
 //! The core module for defining and compiling print data
-use image::DynamicImage;
+use std::{
+    borrow::Cow,
+    io::{self, Write},
+    ops::RangeInclusive,
+    time::Duration,
+};
+
+use image::{DynamicImage, GenericImageView, GrayImage, Luma, Rgb, RgbImage};
 
 #[cfg(feature = "serde")]
 use serde::Deserialize;
 
+#[cfg(feature = "barcode")]
+use crate::barcode::{self, Symbology};
+pub use crate::commands::ColorPower;
 use crate::{
-    commands::{ColorPower, CommandBuilder, DynamicCommandMode, RasterCommand},
+    commands::{
+        CommandBuilder, CommandDescriber, CommandSink, CommandWriter, DynamicCommandMode,
+        PageSplitter, RasterCommand,
+    },
     error::BQLError,
-    media::{Media, MediaSettings, MediaType},
-    raster_image::RasterImage,
+    media::{Media, MediaSettings, MediaType, RASTER_LINE_BYTES},
+    raster_image::{self, RasterImage, RasterLayers},
 };
 
+/// This enum represents non-fatal issues with a print job, see
+/// [compile_with_warnings][PrintJob::compile_with_warnings].
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
+pub enum Warning {
+    /// Compression was requested, but is not supported yet
+    CompressionUnsupported,
+    /// Quality priority was requested, but has no effect on two-color printing
+    QualityPriorityIgnored,
+    /// The page does not contain anything to print
+    BlankPage,
+    /// The whole printable area of the page is covered
+    FullCoverage,
+    /// Two-color media is used, but the page does not contain anything to print in red
+    NoRedContent,
+}
+
+/// Nominal print speed of the QL-820NWB in raster lines per second, i.e. 148mm/s at 300 DPI.
+const NOMINAL_LINES_PER_SEC: f64 = 1748.0;
+/// A raster line without any printed dots
+const BLANK_LINE: [u8; RASTER_LINE_BYTES] = [0; RASTER_LINE_BYTES];
+/// The range of feed margins accepted by the printer in dots at 300 DPI, i.e. 3mm to 127mm.
+const FEED_MARGIN_DOTS: RangeInclusive<u16> = 35..=1500;
+
 /// This enum specifies the cutting behavior for the generated print job.
 #[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
 #[cfg_attr(feature = "serde", derive(Deserialize))]
@@ -20,24 +56,195 @@ pub enum CutBehavior {
     /// Cut after each page
     CutEach,
     /// Cut after every `n` pages. If the `no_pages` attribute of [PrintJob] is not divisible by
-    /// `n` there will be added a cut at the end of the print job.
+    /// `n` there will be added a cut at the end of the print job. The interval is limited to 255
+    /// pages by the printer's command set.
     CutEvery(u8),
     /// Cut after the last page
     CutAtEnd,
 }
 
+/// This enum specifies how images are converted to black and white dots.
+///
+/// On two-color media, only the black layer is affected. Every pixel separated into the red layer
+/// by the [TwoColorThresholds] is printed.
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+pub enum DitherMode {
+    /// Floyd-Steinberg dithering, well suited for photos and gradients
+    #[default]
+    FloydSteinberg,
+    /// Print every pixel whose brightness is below the given cutoff, well suited for text and
+    /// barcodes
+    Threshold(u8),
+    /// No conversion, the image is assumed to be black and white already. Only pure black pixels
+    /// are printed.
+    None,
+}
+
+/// This enum specifies the horizontal alignment of images narrower than the printable width.
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+pub enum Alignment {
+    /// Place the image next to the left margin of the media
+    Left,
+    /// Center the image within the printable width
+    Center,
+    /// Place the image next to the right margin of the media
+    Right,
+}
+
+/// This enum specifies how the image is rotated before it is printed.
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+pub enum Rotation {
+    /// Print the image as is
+    #[default]
+    None,
+    /// Rotate the image by 90 degrees clockwise
+    Cw90,
+    /// Rotate the image by 90 degrees counterclockwise
+    Ccw90,
+    /// Rotate the image by 180 degrees
+    Rot180,
+}
+
+/// This enum specifies the line printed between consecutive pages on continuous media, e.g. as a
+/// guide for cutting the labels manually.
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+pub enum Separator {
+    /// Don't print a separator
+    #[default]
+    None,
+    /// Print a dotted line
+    Dotted,
+    /// Print a solid line
+    Solid,
+}
+
+/// This enum specifies the feed margin added before and after each label on continuous media.
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+pub enum FeedMargin {
+    /// The margin in dots along the feed direction
+    Dots(u16),
+    /// The margin in millimeters, converted to dots at the effective resolution along the feed
+    /// direction, i.e. 300 DPI or 600 DPI in high-DPI mode
+    Millimeters(u8),
+}
+
+impl FeedMargin {
+    fn to_dots(self, high_dpi: bool) -> u16 {
+        match self {
+            FeedMargin::Dots(dots) => dots,
+            FeedMargin::Millimeters(mm) => {
+                let dpi = if high_dpi { 600.0 } else { 300.0 };
+                (f32::from(mm) * dpi / 25.4).round() as u16
+            }
+        }
+    }
+
+    fn is_valid(self, high_dpi: bool) -> bool {
+        Self::range(high_dpi).contains(&self.to_dots(high_dpi))
+    }
+
+    /// Returns the range of feed margins accepted by the printer in dots at the effective
+    /// resolution along the feed direction.
+    fn range(high_dpi: bool) -> RangeInclusive<u16> {
+        let scale = if high_dpi { 2 } else { 1 };
+        FEED_MARGIN_DOTS.start() * scale..=FEED_MARGIN_DOTS.end() * scale
+    }
+}
+
+/// This struct specifies how the colors of an image are separated into the black and red layers
+/// on two-color media. Pixels that are neither black nor red are not printed. Pixels that match
+/// both the black and the red thresholds are printed in red only.
+///
+/// The default only prints pure grays darker than 200 in black and pixels whose red channel is
+/// above 100 and exceeds the other channels in red.
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+pub struct TwoColorThresholds {
+    /// Gray pixels with all channels below this value are printed in black
+    pub black_below: u8,
+    /// The maximum difference between the channels of a pixel that is still considered gray
+    pub gray_tolerance: u8,
+    /// Pixels with a red channel above this value may be printed in red
+    pub red_above: u8,
+    /// The amount by which the red channel has to exceed the green and blue channel for a pixel
+    /// to be printed in red
+    pub red_dominance: u8,
+}
+
+impl Default for TwoColorThresholds {
+    fn default() -> Self {
+        Self {
+            black_below: 200,
+            gray_tolerance: 0,
+            red_above: 100,
+            red_dominance: 0,
+        }
+    }
+}
+
+impl TwoColorThresholds {
+    pub(crate) fn is_black(self, r: u8, g: u8, b: u8) -> bool {
+        let (min, max) = (r.min(g).min(b), r.max(g).max(b));
+        max - min <= self.gray_tolerance && max < self.black_below && !self.is_red(r, g, b)
+    }
+
+    pub(crate) fn is_red(self, r: u8, g: u8, b: u8) -> bool {
+        r > self.red_above
+            && u16::from(r) > u16::from(g) + u16::from(self.red_dominance)
+            && u16::from(r) > u16::from(b) + u16::from(self.red_dominance)
+    }
+}
+
+/// This struct specifies the energy used for printing each layer of a two-color print job.
+///
+/// By default, the black layer is printed with [HighEnergy][ColorPower::HighEnergy] and the red
+/// layer with [LowEnergy][ColorPower::LowEnergy]. Some red tapes may need a different
+/// configuration.
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+pub struct TwoColorEnergy {
+    /// The energy used for the black layer
+    pub black: ColorPower,
+    /// The energy used for the red layer
+    pub red: ColorPower,
+}
+
+impl Default for TwoColorEnergy {
+    fn default() -> Self {
+        Self {
+            black: ColorPower::HighEnergy,
+            red: ColorPower::LowEnergy,
+        }
+    }
+}
+
 /// This struct defines the general settings for the generated print job.
 #[derive(Clone, PartialEq, Debug)]
 pub struct PrintJob {
-    /// The amount of replicas to print
-    pub no_pages: u8,
+    /// The amount of replicas to print, at least one
+    pub no_pages: u16,
     /// The image to print. The required type is [DynamicImage] from the [image] crate.
     pub image: DynamicImage,
+    /// An optional, pre-separated image for the red layer of two-color media.
+    ///
+    /// If set, no color separation is performed: [image][PrintJob::image] is printed in black and
+    /// this image in red. Both images are treated like monochrome labels, i.e. every non-white
+    /// pixel is printed, and both have to match the media dimensions.
+    pub red_image: Option<DynamicImage>,
     /// The paper type to use for the print job
     pub media: Media,
     /// Whether or not to use high-DPI mode. The image file will need to be double the resolution along
     /// its length. Probably not recommended.
     pub high_dpi: bool,
+    /// Whether or not to use high-DPI mode with a regular resolution image. Every raster line is
+    /// printed twice, so the image does not need to be upscaled along its length. Implies
+    /// [high_dpi][PrintJob::high_dpi].
+    pub high_dpi_upscale: bool,
     /// Whether or not to use compression
     ///
     /// NOTE:
@@ -48,98 +255,1406 @@ pub struct PrintJob {
     pub quality_priority: bool,
     /// The selected behavior for the automatic cutter unit
     pub cut_behaviour: CutBehavior,
+    /// An optional per-page override of [cut_behaviour][PrintJob::cut_behaviour]. The printer cuts
+    /// after every page whose entry is `true` and after no other page, including pages beyond the
+    /// end of the mask. The entry of the last page decides whether the job ends with a cut.
+    pub cut_mask: Option<Vec<bool>>,
+    /// Whether or not to repeat the invalidate and initialize preamble before every page instead
+    /// of sending it once at the start of the job. This makes every page self-contained, which
+    /// some print spoolers expect.
+    pub preamble_per_page: bool,
+    /// The energy used for printing the black and red layers. Has no effect on monochrome
+    /// printing.
+    pub two_color_energy: TwoColorEnergy,
+    /// The feed margin for continuous media. If [None], the default of 3mm is used, i.e. 35 dots
+    /// or 70 dots in high-DPI mode. Die-cut labels are always printed without a feed margin.
+    ///
+    /// The margin has to be between 3mm and 127mm, i.e. 35 to 1500 dots, or twice as many dots
+    /// in high-DPI mode.
+    pub feed_margin: Option<FeedMargin>,
+    /// Whether or not to send blank raster lines using the short zero raster graphics command.
+    /// This greatly reduces the size of labels with large blank areas, but is not supported by
+    /// every printer model. Only applies to monochrome printing.
+    pub optimize_blank_lines: bool,
+    /// The maximum difference in dots between the image and the media dimensions that is
+    /// tolerated. Images within the tolerance are padded with white or cropped to fit, keeping
+    /// them centered. A tolerance of zero requires the image to match exactly.
+    pub dimension_tolerance: u32,
+    /// Whether or not to automatically scale the image to the printable width of the media,
+    /// preserving its aspect ratio. On die-cut media, the image is scaled down further if
+    /// necessary and centered on the label, padded with white. Overrides
+    /// [dimension_tolerance][PrintJob::dimension_tolerance].
+    pub auto_resize: bool,
+    /// How the image is converted to black and white dots
+    pub dither_mode: DitherMode,
+    /// The horizontal alignment of the image within the printable width of the media.
+    ///
+    /// If [None], the image has to match the printable width. Otherwise, narrower images are
+    /// accepted and placed accordingly.
+    pub alignment: Option<Alignment>,
+    /// The amount added to every color channel before the image is converted to dots, between
+    /// -255 and 255. Positive values lighten the image. Applied after
+    /// [gamma][PrintJob::gamma] and before [contrast][PrintJob::contrast].
+    pub brightness: i32,
+    /// The contrast adjustment in percent applied before the image is converted to dots.
+    /// Positive values increase the contrast, -100 turns the image into uniform gray. Must be
+    /// finite.
+    pub contrast: f32,
+    /// The gamma correction applied before the image is converted to dots. Must be positive.
+    /// Values above 1.0 lighten the midtones, values below 1.0 darken them.
+    pub gamma: f32,
+    /// Whether or not to feed the media after the last page. If disabled, the job ends like every
+    /// other page, so consecutive jobs can be printed without wasting tape in between.
+    ///
+    /// NOTE:
+    /// The end of the last label may then still be under the print head and has to be fed
+    /// manually before tearing it off.
+    pub feed_at_end: bool,
+    /// Whether or not to print only the circle inscribed in die-cut labels, so nothing is printed
+    /// beyond the edge of round labels. If [None], this is enabled for round media like
+    /// [D24][Media::D24]. Has no effect on continuous media.
+    pub circular_mask: Option<bool>,
+    /// How colors are separated into the black and red layers on two-color media. Has no effect
+    /// on monochrome printing or if [red_image][PrintJob::red_image] is set.
+    pub two_color_thresholds: TwoColorThresholds,
+    /// The rotation applied to the image before anything else. The dimensions of the rotated
+    /// image have to match the media, e.g. a landscape image rotated by 90 degrees.
+    pub rotation: Rotation,
+    /// A signed adjustment in dots of the left margin of the media, e.g. to compensate for the
+    /// mechanical tolerances of a printer. Positive values move the image towards the right
+    /// margin. The image is never moved beyond the print head.
+    pub margin_offset: i32,
+    /// The line printed after every page except the last one. The separator is added to the
+    /// length of the page. Has no effect on die-cut media.
+    pub separator: Separator,
+    /// The color transparent parts of the image are composited over, white by default. Fully
+    /// transparent pixels get exactly this color. Has no effect on images without an alpha
+    /// channel.
+    pub background: Rgb<u8>,
+    /// If set, blank lines at the bottom of the image are removed on continuous media, so the
+    /// label is only as long as its content. The given number of blank lines is kept as bottom
+    /// margin. Has no effect on die-cut media.
+    pub trim_blank: Option<u32>,
 }
 
 impl PrintJob {
+    /// Create a single-page print job for `image` on `media`.
+    ///
+    /// All other settings are disabled or use their default, except for a cut after the last page.
+    /// Use struct update syntax to override individual settings.
+    pub fn new(image: DynamicImage, media: Media) -> Self {
+        Self {
+            no_pages: 1,
+            image,
+            red_image: None,
+            media,
+            high_dpi: false,
+            high_dpi_upscale: false,
+            compressed: false,
+            quality_priority: false,
+            cut_behaviour: CutBehavior::CutAtEnd,
+            cut_mask: None,
+            preamble_per_page: false,
+            two_color_energy: TwoColorEnergy::default(),
+            feed_margin: None,
+            optimize_blank_lines: false,
+            dimension_tolerance: 0,
+            auto_resize: false,
+            dither_mode: DitherMode::default(),
+            alignment: None,
+            brightness: 0,
+            contrast: 0.0,
+            gamma: 1.0,
+            feed_at_end: true,
+            circular_mask: None,
+            two_color_thresholds: TwoColorThresholds::default(),
+            rotation: Rotation::default(),
+            margin_offset: 0,
+            separator: Separator::default(),
+            background: Rgb([255, 255, 255]),
+            trim_blank: None,
+        }
+    }
+
+    /// Create a print job for a barcode encoding `data` that fills the printable width of `media`.
+    ///
+    /// See [render][crate::barcode::render] for the layout of the barcode. The
+    /// [circular_mask][PrintJob::circular_mask] is disabled, so no modules are cut off on round
+    /// labels.
+    #[cfg(feature = "barcode")]
+    pub fn barcode(
+        data: &str,
+        symbology: Symbology,
+        media: Media,
+        human_readable: bool,
+    ) -> Result<Self, BQLError> {
+        let image = barcode::render(data, symbology, media, human_readable)?;
+        Ok(Self {
+            circular_mask: Some(false),
+            ..Self::new(image, media)
+        })
+    }
+
+    /// Create a print job whose page is completely covered with a single solid color, e.g. for
+    /// blackout or redaction labels.
+    ///
+    /// If `black` is `false`, the page is filled with red instead, which requires two-color media.
+    /// Die-cut labels are filled exactly, so [circular_mask][PrintJob::circular_mask] is disabled
+    /// even for round labels. Continuous media has no fixed label length, so a square page as long
+    /// as the media is wide is produced. The job uses a
+    /// [threshold][DitherMode::Threshold] that prints every pixel of the fill, so it is not
+    /// dithered.
+    pub fn solid_fill(media: Media, black: bool) -> Result<Self, BQLError> {
+        let media_settings = MediaSettings::new(&media)?;
+        if !black && !media_settings.color {
+            return Err(BQLError::TwoColorUnsupported);
+        }
+        let (width, length) = page_size(&media_settings);
+        let fill = if black { [0, 0, 0] } else { [255, 0, 0] };
+        let image = RgbImage::from_pixel(width, length, Rgb(fill));
+        Ok(Self {
+            dither_mode: DitherMode::Threshold(u8::MAX),
+            circular_mask: Some(false),
+            ..Self::new(DynamicImage::ImageRgb8(image), media)
+        })
+    }
+
+    /// Compiles a print job for a fixed synthetic test pattern on the given media.
+    ///
+    /// The pattern consists of diagonal black and white stripes, so the output does not depend on
+    /// dithering or color separation. The page has the same size as for
+    /// [solid_fill][PrintJob::solid_fill]. The output can be stored as a golden file to detect
+    /// unintended changes of the command emission.
+    pub fn golden_bytes(media: Media) -> Result<Vec<u8>, BQLError> {
+        let media_settings = MediaSettings::new(&media)?;
+        let (width, length) = page_size(&media_settings);
+        let image = GrayImage::from_fn(width, length, |x, y| {
+            Luma([if (x + y) / 16 % 2 == 0 { 0 } else { 255 }])
+        });
+        Self::new(DynamicImage::ImageLuma8(image), media).compile()
+    }
+
+    /// Create a single-page two-color print job from two pre-separated images.
+    ///
+    /// See [red_image][PrintJob::red_image] for how the images are interpreted.
+    pub fn two_color(black: DynamicImage, red: DynamicImage, media: Media) -> Self {
+        Self {
+            red_image: Some(red),
+            ..Self::new(black, media)
+        }
+    }
+
+    /// Split a tall image into consecutive segments of `segment_length_dots` lines and create a
+    /// single-page print job with the default settings for each of them.
+    ///
+    /// If the image height is not divisible by the segment length, the last segment is shorter.
+    /// Returns an error if `segment_length_dots` is zero.
+    pub fn from_image_segmented(
+        img: DynamicImage,
+        media: Media,
+        segment_length_dots: u32,
+    ) -> Result<Vec<Self>, BQLError> {
+        if segment_length_dots == 0 {
+            return Err(BQLError::ZeroSegmentLength);
+        }
+        let (width, height) = img.dimensions();
+        Ok((0..height)
+            .step_by(segment_length_dots as usize)
+            .map(|y| {
+                let segment = img.crop_imm(0, y, width, segment_length_dots.min(height - y));
+                Self::new(segment, media)
+            })
+            .collect())
+    }
+
+    /// Create a print job with the same settings and image as this one, but for a different
+    /// media, e.g. the one actually loaded into the printer.
+    ///
+    /// The image is scaled to the new media, see [auto_resize][PrintJob::auto_resize]. Returns an
+    /// error if the media is not supported or a [red_image][PrintJob::red_image] is set, but the
+    /// media does not support two-color printing.
+    pub fn rebuild_for(&self, media: Media) -> Result<Self, BQLError> {
+        let media_settings = MediaSettings::new(&media)?;
+        if self.red_image.is_some() && !media_settings.color {
+            return Err(BQLError::TwoColorUnsupported);
+        }
+        Ok(PrintJob {
+            media,
+            auto_resize: true,
+            ..self.clone()
+        })
+    }
+
+    /// Create a print job with the same settings as this one, but for a different image.
+    ///
+    /// A pre-separated [red_image][PrintJob::red_image] is not carried over. Returns an error if
+    /// the image does not match the media dimensions.
+    pub fn with_image(&self, image: DynamicImage) -> Result<Self, BQLError> {
+        let job = PrintJob {
+            image,
+            red_image: None,
+            ..self.clone()
+        };
+        let media_settings = MediaSettings::new(&job.media)?;
+        if !job.auto_resize {
+            let image = raster_image::rotate(Cow::Borrowed(&job.image), job.rotation);
+            raster_image::check_dimensions(&image, &job, &media_settings)?;
+        }
+        Ok(job)
+    }
+
+    /// Split a two-color print job into two monochrome print jobs, one for the black and one for
+    /// the red parts of the label, e.g. to print them in two separate runs.
+    ///
+    /// Both jobs keep all other settings. Returns an error if the media does not support two-color
+    /// printing.
+    pub fn into_color_passes(mut self) -> Result<(PrintJob, PrintJob), BQLError> {
+        if !MediaSettings::new(&self.media)?.color {
+            return Err(BQLError::TwoColorUnsupported);
+        }
+        let image = std::mem::take(&mut self.image);
+        let (black, red) = match self.red_image.take() {
+            Some(red_image) => (image, red_image),
+            None => raster_image::separate_colors(&image, self.two_color_thresholds),
+        };
+        self.media = self.media.monochrome();
+        let black_job = PrintJob {
+            image: black,
+            ..self.clone()
+        };
+        let red_job = PrintJob { image: red, ..self };
+        Ok((black_job, red_job))
+    }
+
+    /// Returns the amount of blank dots `(left, right)` surrounding the image within each raster
+    /// line.
+    ///
+    /// For an image that fits the media, both margins and the image width add up to the full line.
+    pub fn effective_margins(&self) -> Result<(u32, u32), BQLError> {
+        let media_settings = MediaSettings::new(&self.media)?;
+        raster_image::effective_margins(self, &media_settings)
+    }
+
+    /// Returns the amount of bytes that have to be sent to the printer for this print job, i.e.
+    /// the length of the compiled data.
+    ///
+    /// This crate does not exchange any status messages with the printer. If you request the status
+    /// between pages, every status request adds 3 bytes sent and every reply 32 bytes received.
+    pub fn wire_size(&self) -> Result<usize, BQLError> {
+        Ok(self.commands()?.len())
+    }
+
+    /// Estimates how long it takes to send this print job over a link with the given throughput.
+    pub fn estimated_transmit_time(&self, bytes_per_sec: u32) -> Result<Duration, BQLError> {
+        let secs = self.wire_size()? as f64 / f64::from(bytes_per_sec);
+        Ok(Duration::try_from_secs_f64(secs).unwrap_or(Duration::MAX))
+    }
+
+    /// Estimates the length of tape in millimeters this print job consumes.
+    ///
+    /// On continuous media, this is the length of all pages including the feed margin before and
+    /// after each page and the [separator][PrintJob::separator] lines between pages. On die-cut
+    /// media, this is the fixed length of a single label, regardless of the amount of pages.
+    pub fn estimated_length_mm(&self) -> Result<f32, BQLError> {
+        let media_settings = MediaSettings::new(&self.media)?;
+        match media_settings.media_type {
+            MediaType::Continuous => {
+                let dpi = if self.uses_high_dpi() { 600.0 } else { 300.0 };
+                let page_lines = self.raster_image()?.no_lines() as f32
+                    + 2.0 * f32::from(self.margin_dots(&media_settings));
+                // The separator is printed between pages, i.e. not after the last one
+                let separator_lines = raster_image::separator_lines(self, &media_settings).len();
+                let lines = page_lines * f32::from(self.no_pages)
+                    + separator_lines as f32 * f32::from(self.no_pages.saturating_sub(1));
+                Ok(lines / dpi * 25.4)
+            }
+            MediaType::DieCut { length_mm, .. } => Ok(f32::from(length_mm)),
+        }
+    }
+
+    /// Roughly estimates how long the printer takes to print this job.
+    ///
+    /// The estimate is based on the nominal print speed of the QL-820NWB, assuming half the
+    /// speed if [quality_priority][PrintJob::quality_priority] is set. Feeding, cutting and
+    /// cooling pauses are not taken into account. Returns an error if the image cannot be
+    /// rasterized for the media.
+    pub fn estimated_print_duration(&self) -> Result<Duration, BQLError> {
+        let mut lines = f64::from(self.raster_image()?.no_lines()) * f64::from(self.no_pages);
+        // The media advances half as far per raster line in high-DPI mode
+        if self.uses_high_dpi() {
+            lines /= 2.0;
+        }
+        let mut secs = lines / NOMINAL_LINES_PER_SEC;
+        if self.quality_priority {
+            secs *= 2.0;
+        }
+        Ok(Duration::from_secs_f64(secs))
+    }
+
     /// Create a compiled print job from the specified settings.
     ///
     /// The resulting [`Vec<u8>`] can be directly send to your printer's serial or network interface,
     /// e.g. using `nc`.
     pub fn compile(self) -> Result<Vec<u8>, BQLError> {
-        let media_settings = MediaSettings::new(&self.media);
-        let height = self.image.height();
-        let raster_image = RasterImage::new(self.image, &media_settings)?;
+        Ok(self.commands()?.build())
+    }
+
+    /// Like [compile][PrintJob::compile], but returns the preamble and the data of every page
+    /// separately, e.g. to check the printer status between pages.
+    ///
+    /// The preamble has to be sent first, followed by the pages in order. Together, they are
+    /// identical to the result of [compile][PrintJob::compile].
+    pub fn compile_pages(self) -> Result<(Vec<u8>, Vec<Vec<u8>>), BQLError> {
+        let (media_settings, raster_image) = self.rasterize()?;
+        let mut commands = PageSplitter::default();
+        self.add_commands(&media_settings, &raster_image, &mut commands);
+        Ok(commands.build())
+    }
 
+    /// Like [compile][PrintJob::compile], but prints the given, previously rasterized image
+    /// instead of [image][PrintJob::image].
+    ///
+    /// Settings that only affect rasterization, e.g. [dither_mode][PrintJob::dither_mode], are
+    /// ignored. Returns an error if the image was rasterized for a different media or resolution.
+    pub fn compile_raster(&self, raster_image: &RasterImage) -> Result<Vec<u8>, BQLError> {
+        self.validate()?;
+        if raster_image.media() != self.media || raster_image.high_dpi() != self.uses_high_dpi() {
+            return Err(BQLError::DimensionMismatch);
+        }
+        let media_settings = MediaSettings::new(&self.media)?;
         let mut commands = CommandBuilder::default();
+        self.add_commands(&media_settings, raster_image, &mut commands);
+        Ok(commands.build())
+    }
+
+    /// Rasterizes [image][PrintJob::image] using the settings of this print job, e.g. to print it
+    /// repeatedly with [compile_raster][PrintJob::compile_raster].
+    pub fn raster_image(&self) -> Result<RasterImage, BQLError> {
+        self.validate_adjustments()?;
+        let media_settings = MediaSettings::new(&self.media)?;
+        RasterImage::new(self, &media_settings)
+    }
+
+    /// Returns an image of every page as it is printed, reconstructed from the raster data, e.g.
+    /// to check margins and dithering. See [RasterImage::to_image] for the image layout.
+    pub fn preview(&self) -> Result<Vec<DynamicImage>, BQLError> {
+        let image = self.raster_image()?.to_image();
+        Ok(vec![image; usize::from(self.no_pages)])
+    }
+
+    /// Like [preview][PrintJob::preview], but renders the parts printed in red in red, e.g. to
+    /// check the color separation on two-color media.
+    pub fn preview_color(&self) -> Result<Vec<DynamicImage>, BQLError> {
+        let image = self.raster_image()?.to_color_image();
+        Ok(vec![image; usize::from(self.no_pages)])
+    }
+
+    /// Returns a human-readable listing of the commands [compile][PrintJob::compile] would emit,
+    /// one entry per command, e.g. `SpecifyMarginAmount(35)` or
+    /// `RasterGraphicsTransfer(90 bytes)`. This is intended for debugging.
+    pub fn describe(&self) -> Result<Vec<String>, BQLError> {
+        let (media_settings, raster_image) = self.rasterize()?;
+        let mut commands = CommandDescriber::default();
+        self.add_commands(&media_settings, &raster_image, &mut commands);
+        Ok(commands.build())
+    }
+
+    /// Like [compile][PrintJob::compile], but writes the compiled data directly to `writer`
+    /// instead of collecting it in memory first.
+    ///
+    /// If the print job is invalid, an error of kind [InvalidInput][io::ErrorKind::InvalidInput]
+    /// wrapping the [BQLError] is returned before anything is written.
+    pub fn compile_to<W: Write>(self, writer: &mut W) -> io::Result<()> {
+        let (media_settings, raster_image) = self
+            .rasterize()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let mut commands = CommandWriter::new(writer);
+        self.add_commands(&media_settings, &raster_image, &mut commands);
+        commands.finish()
+    }
+
+    /// Like [compile][PrintJob::compile], but additionally returns non-fatal issues with the print
+    /// job that may lead to unexpected results.
+    pub fn compile_with_warnings(self) -> Result<(Vec<u8>, Vec<Warning>), BQLError> {
+        let (media_settings, raster_image) = self.rasterize()?;
+        let mut warnings = Vec::new();
+        if self.compressed {
+            warnings.push(Warning::CompressionUnsupported);
+        }
+        if self.quality_priority && media_settings.color {
+            warnings.push(Warning::QualityPriorityIgnored);
+        }
+        let (black_dots, red_dots) = raster_image.dot_counts();
+        if black_dots + red_dots == 0 {
+            warnings.push(Warning::BlankPage);
+        } else if black_dots + red_dots
+            >= media_settings.width_dots as usize * raster_image.no_lines() as usize
+        {
+            warnings.push(Warning::FullCoverage);
+        }
+        if media_settings.color && red_dots == 0 {
+            warnings.push(Warning::NoRedContent);
+        }
+        let mut commands = CommandBuilder::default();
+        self.add_commands(&media_settings, &raster_image, &mut commands);
+        Ok((commands.build(), warnings))
+    }
+
+    /// Checks the settings that do not depend on the image.
+    fn validate(&self) -> Result<(), BQLError> {
+        if self.no_pages == 0 {
+            return Err(BQLError::ZeroPages);
+        }
+        if self.cut_behaviour == CutBehavior::CutEvery(0) {
+            return Err(BQLError::ZeroCutInterval);
+        }
+        if let Some(feed_margin) = self.feed_margin {
+            if !feed_margin.is_valid(self.uses_high_dpi()) {
+                return Err(BQLError::InvalidFeedMargin);
+            }
+        }
+        self.validate_adjustments()
+    }
+
+    /// Checks the settings applied to the image before it is converted to dots.
+    fn validate_adjustments(&self) -> Result<(), BQLError> {
+        if !(self.gamma.is_finite() && self.gamma > 0.0) {
+            return Err(BQLError::InvalidGamma);
+        }
+        if !(-255..=255).contains(&self.brightness) {
+            return Err(BQLError::InvalidBrightness);
+        }
+        if !self.contrast.is_finite() {
+            return Err(BQLError::InvalidContrast);
+        }
+        Ok(())
+    }
+
+    fn rasterize(&self) -> Result<(MediaSettings, RasterImage), BQLError> {
+        self.validate()?;
+        let media_settings = MediaSettings::new(&self.media)?;
+        let raster_image = RasterImage::new(self, &media_settings)?;
+        Ok((media_settings, raster_image))
+    }
 
+    fn commands(&self) -> Result<CommandBuilder, BQLError> {
+        let (media_settings, raster_image) = self.rasterize()?;
+        let mut commands = CommandBuilder::default();
+        self.add_commands(&media_settings, &raster_image, &mut commands);
+        Ok(commands)
+    }
+
+    fn uses_high_dpi(&self) -> bool {
+        self.high_dpi || self.high_dpi_upscale
+    }
+
+    /// Returns the feed margin in dots at the effective resolution along the feed direction.
+    fn margin_dots(&self, media_settings: &MediaSettings) -> u16 {
+        match media_settings.media_type {
+            MediaType::Continuous => {
+                let high_dpi = self.uses_high_dpi();
+                // Default to the smallest margin supported by the printer, i.e. 3mm
+                self.feed_margin
+                    .map_or(*FeedMargin::range(high_dpi).start(), |margin| {
+                        margin.to_dots(high_dpi)
+                    })
+            }
+            MediaType::DieCut { .. } => 0,
+        }
+    }
+
+    /// Returns whether auto cut is enabled, the cut interval and whether to cut at the end for
+    /// the given page.
+    fn cut_settings(&self, page_no: u16) -> (bool, Option<u8>, bool) {
+        if let Some(cut_mask) = &self.cut_mask {
+            let cuts_after = |page_no: u16| cut_mask.get(usize::from(page_no)) == Some(&true);
+            let cut = cuts_after(page_no);
+            return (cut, cut.then_some(1), cuts_after(self.no_pages - 1));
+        }
+        match self.cut_behaviour {
+            CutBehavior::None => (false, None, false),
+            CutBehavior::CutEach => (true, Some(1), false),
+            CutBehavior::CutEvery(n) => (true, Some(n), !self.no_pages.is_multiple_of(n.into())),
+            CutBehavior::CutAtEnd => (true, None, true),
+        }
+    }
+
+    fn add_commands(
+        &self,
+        media_settings: &MediaSettings,
+        raster_image: &RasterImage,
+        commands: &mut impl CommandSink,
+    ) {
         use RasterCommand::*;
-        commands.add(Invalidate);
-        commands.add(Initialize);
+        let separator = raster_image::separator_lines(self, media_settings);
         for page_no in 0..self.no_pages {
+            let separator = if page_no == self.no_pages - 1 {
+                &[][..]
+            } else {
+                &separator[..]
+            };
+            if page_no == 0 || self.preamble_per_page {
+                commands.add(Invalidate);
+                commands.add(Initialize);
+            }
             commands.add(SwitchDynamicCommandMode {
                 command_mode: DynamicCommandMode::Raster,
             });
             commands.add(SwitchAutomaticStatusNotificationMode { notify: false });
             commands.add(PrintInformation {
-                media_settings,
-                quality_priority: match raster_image {
-                    RasterImage::Monochrome { .. } => self.quality_priority,
-                    RasterImage::TwoColor { .. } => false,
+                media_settings: *media_settings,
+                quality_priority: match raster_image.layers {
+                    RasterLayers::Monochrome { .. } => self.quality_priority,
+                    RasterLayers::TwoColor { .. } => false,
                 },
                 recovery_on: true,
-                no_lines: height,
+                no_lines: raster_image.no_lines() + separator.len() as u32,
                 first_page: page_no == 0,
             });
-            commands.add(VariousMode {
-                auto_cut: self.cut_behaviour != CutBehavior::None,
-            });
-            match self.cut_behaviour {
-                CutBehavior::CutEvery(n) => {
-                    commands.add(SpecifyPageNumber { cut_every: n });
-                }
-                CutBehavior::CutEach => {
-                    commands.add(SpecifyPageNumber { cut_every: 1 });
-                }
-                _ => {}
+            let (auto_cut, cut_every, cut_at_end) = self.cut_settings(page_no);
+            commands.add(VariousMode { auto_cut });
+            if let Some(cut_every) = cut_every {
+                commands.add(SpecifyPageNumber { cut_every });
             }
             commands.add(ExpandedMode {
                 two_color: media_settings.color,
-                cut_at_end: match self.cut_behaviour {
-                    CutBehavior::CutAtEnd => true,
-                    CutBehavior::CutEvery(n) => self.no_pages % n != 0,
-                    _ => false,
-                },
-                high_dpi: self.high_dpi,
+                cut_at_end,
+                high_dpi: self.uses_high_dpi(),
             });
             commands.add(SpecifyMarginAmount {
-                margin_size: match media_settings.media_type {
-                    MediaType::Continuous => 35,
-                    MediaType::DieCut { .. } => 0,
-                },
+                margin_size: self.margin_dots(media_settings),
             });
             commands.add(SelectCompressionMode {
                 // TODO: Add support for compression
                 tiff_compression: false,
             });
-            match &raster_image {
-                RasterImage::Monochrome { black_layer } => black_layer.iter().for_each(|line| {
-                    commands.add(RasterGraphicsTransfer {
-                        data: line.to_vec(),
+            match &raster_image.layers {
+                RasterLayers::Monochrome { black_layer } => {
+                    black_layer.iter().chain(separator).for_each(|line| {
+                        if self.optimize_blank_lines && line.iter().all(|&byte| byte == 0) {
+                            commands.add(ZeroRasterGraphics)
+                        } else {
+                            commands.add(RasterGraphicsTransfer {
+                                data: line.to_vec(),
+                            })
+                        }
                     })
-                }),
-                RasterImage::TwoColor {
+                }
+                RasterLayers::TwoColor {
                     black_layer,
                     red_layer,
                 } => black_layer
                     .iter()
-                    .zip(red_layer.iter())
+                    .chain(separator)
+                    .zip(
+                        red_layer
+                            .iter()
+                            .chain(separator.iter().map(|_| &BLANK_LINE)),
+                    )
                     .for_each(|(black_line, red_line)| {
                         commands.add(TwoColorRasterGraphicsTransfer {
                             data: black_line.to_vec(),
-                            color_power: ColorPower::HighEnergy,
+                            color_power: self.two_color_energy.black,
                         });
                         commands.add(TwoColorRasterGraphicsTransfer {
                             data: red_line.to_vec(),
-                            color_power: ColorPower::LowEnergy,
+                            color_power: self.two_color_energy.red,
                         })
                     }),
             };
-            if page_no == self.no_pages - 1 {
+            if page_no == self.no_pages - 1 && self.feed_at_end {
                 commands.add(PrintWithFeed)
             } else {
                 commands.add(Print)
             };
         }
-        Ok(commands.build())
+    }
+}
+
+/// Returns the page size for generated images: die-cut labels are filled exactly, continuous
+/// media gets a square page.
+fn page_size(media_settings: &MediaSettings) -> (u32, u32) {
+    let length = match media_settings.media_type {
+        MediaType::Continuous => media_settings.width_dots,
+        MediaType::DieCut { length_dots, .. } => length_dots,
+    };
+    (media_settings.width_dots, length)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the amount of dots in the printable area of a rasterized job.
+    fn printable_dots(job: &PrintJob) -> usize {
+        let media_settings = MediaSettings::new(&job.media).unwrap();
+        let raster_image = job.raster_image().unwrap();
+        media_settings.width_dots as usize * raster_image.no_lines() as usize
+    }
+
+    #[test]
+    fn solid_fill_covers_die_cut_label() {
+        let job = PrintJob::solid_fill(Media::D24, true).unwrap();
+        let (black_dots, red_dots) = job.raster_image().unwrap().dot_counts();
+        assert_eq!(black_dots, printable_dots(&job));
+        assert_eq!(red_dots, 0);
+    }
+
+    #[test]
+    fn solid_red_fill_only_covers_red_layer() {
+        let job = PrintJob::solid_fill(Media::C62R, false).unwrap();
+        let (black_dots, red_dots) = job.raster_image().unwrap().dot_counts();
+        assert_eq!(black_dots, 0);
+        assert_eq!(red_dots, printable_dots(&job));
+    }
+
+    #[test]
+    fn golden_bytes_match_fixtures() {
+        let c62 = PrintJob::golden_bytes(Media::C62).unwrap();
+        assert!(c62 == include_bytes!("../tests/fixtures/golden_c62.bin"));
+        let d24 = PrintJob::golden_bytes(Media::D24).unwrap();
+        assert!(d24 == include_bytes!("../tests/fixtures/golden_d24.bin"));
+    }
+
+    fn blank_image(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageLuma8(GrayImage::from_pixel(width, height, Luma([255])))
+    }
+
+    #[test]
+    fn print_duration_grows_with_quality_priority() {
+        let job = PrintJob::new(blank_image(696, 300), Media::C62);
+        let quality_job = PrintJob {
+            quality_priority: true,
+            ..job.clone()
+        };
+        assert!(
+            quality_job.estimated_print_duration().unwrap()
+                > job.estimated_print_duration().unwrap()
+        );
+    }
+
+    #[test]
+    fn print_duration_grows_with_image_length() {
+        let short_job = PrintJob::new(blank_image(696, 300), Media::C62);
+        let long_job = PrintJob::new(blank_image(696, 3000), Media::C62);
+        assert!(
+            long_job.estimated_print_duration().unwrap()
+                > short_job.estimated_print_duration().unwrap()
+        );
+        // The length along the feed direction counts, not the height of the unrotated image
+        let rotated_job = PrintJob {
+            rotation: Rotation::Cw90,
+            ..PrintJob::new(blank_image(3000, 696), Media::C62)
+        };
+        assert_eq!(
+            rotated_job.estimated_print_duration().unwrap(),
+            long_job.estimated_print_duration().unwrap()
+        );
+    }
+
+    /// Returns the amount of black and red dots of a two-color job filled with a single color.
+    fn two_color_dots(color: [u8; 3], two_color_thresholds: TwoColorThresholds) -> (usize, usize) {
+        let image = RgbImage::from_pixel(696, 10, Rgb(color));
+        let job = PrintJob {
+            dither_mode: DitherMode::Threshold(u8::MAX),
+            two_color_thresholds,
+            ..PrintJob::new(DynamicImage::ImageRgb8(image), Media::C62R)
+        };
+        job.raster_image().unwrap().dot_counts()
+    }
+
+    #[test]
+    fn pink_is_printed_in_red_after_lowering_threshold() {
+        let pink = [255, 182, 193];
+        let strict = TwoColorThresholds {
+            red_dominance: 100,
+            ..Default::default()
+        };
+        assert_eq!(two_color_dots(pink, strict), (0, 0));
+        let tuned = TwoColorThresholds {
+            red_dominance: 50,
+            ..strict
+        };
+        assert_eq!(two_color_dots(pink, tuned), (0, 696 * 10));
+    }
+
+    #[test]
+    fn red_takes_precedence_over_black() {
+        let thresholds = TwoColorThresholds {
+            gray_tolerance: 100,
+            ..Default::default()
+        };
+        assert_eq!(two_color_dots([150, 60, 60], thresholds), (0, 696 * 10));
+        assert_eq!(two_color_dots([60, 60, 60], thresholds), (696 * 10, 0));
+    }
+
+    #[test]
+    fn zero_pages_are_rejected() {
+        let job = PrintJob {
+            no_pages: 0,
+            ..PrintJob::new(blank_image(696, 10), Media::C62)
+        };
+        assert_eq!(job.compile(), Err(BQLError::ZeroPages));
+    }
+
+    #[test]
+    fn thousand_copies_compile_to_thousand_pages() {
+        let job = PrintJob {
+            no_pages: 1000,
+            ..PrintJob::new(blank_image(696, 10), Media::C62)
+        };
+        let (_, pages) = job.clone().compile_pages().unwrap();
+        assert_eq!(pages.len(), 1000);
+        assert_eq!(job.preview().unwrap().len(), 1000);
+    }
+
+    #[test]
+    fn segments_keep_the_remainder() {
+        let jobs = PrintJob::from_image_segmented(blank_image(696, 500), Media::C62, 200).unwrap();
+        let no_lines = jobs
+            .iter()
+            .map(|job| job.raster_image().unwrap().no_lines())
+            .collect::<Vec<_>>();
+        assert_eq!(no_lines, [200, 200, 100]);
+    }
+
+    #[test]
+    fn zero_segment_length_is_rejected() {
+        assert_eq!(
+            PrintJob::from_image_segmented(blank_image(696, 500), Media::C62, 0),
+            Err(BQLError::ZeroSegmentLength)
+        );
+    }
+
+    #[test]
+    fn non_positive_gamma_is_rejected() {
+        for gamma in [0.0, -1.0, f32::NAN] {
+            let job = PrintJob {
+                gamma,
+                ..PrintJob::new(blank_image(696, 10), Media::C62)
+            };
+            assert_eq!(job.compile(), Err(BQLError::InvalidGamma));
+        }
+    }
+
+    #[test]
+    fn out_of_range_brightness_is_rejected() {
+        for brightness in [-256, 256, i32::MIN, i32::MAX] {
+            let job = PrintJob {
+                brightness,
+                ..PrintJob::new(blank_image(696, 10), Media::C62)
+            };
+            assert_eq!(job.compile(), Err(BQLError::InvalidBrightness));
+        }
+        for brightness in [-255, 255] {
+            let job = PrintJob {
+                brightness,
+                ..PrintJob::new(blank_image(696, 10), Media::C62)
+            };
+            assert!(job.compile().is_ok());
+        }
+    }
+
+    #[test]
+    fn non_finite_contrast_is_rejected() {
+        for contrast in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            let job = PrintJob {
+                contrast,
+                ..PrintJob::new(blank_image(696, 10), Media::C62)
+            };
+            assert_eq!(job.raster_image(), Err(BQLError::InvalidContrast));
+            assert_eq!(job.compile(), Err(BQLError::InvalidContrast));
+        }
+    }
+
+    #[test]
+    fn brightness_reduces_black_dots() {
+        let gray = GrayImage::from_pixel(696, 100, Luma([128]));
+        let black_dots = |brightness| {
+            let job = PrintJob {
+                brightness,
+                ..PrintJob::new(DynamicImage::ImageLuma8(gray.clone()), Media::C62)
+            };
+            job.raster_image().unwrap().dot_counts().0
+        };
+        assert!(black_dots(60) < black_dots(0));
+        assert!(black_dots(-60) > black_dots(0));
+    }
+
+    #[test]
+    fn length_includes_feed_margins_and_separators() {
+        let job = PrintJob {
+            no_pages: 3,
+            ..PrintJob::new(blank_image(696, 300), Media::C62)
+        };
+        let length_mm = job.estimated_length_mm().unwrap();
+        assert!((length_mm - 3.0 * 370.0 / 300.0 * 25.4).abs() < 0.01);
+        let separated_job = PrintJob {
+            separator: Separator::Solid,
+            ..job
+        };
+        let separated_length_mm = separated_job.estimated_length_mm().unwrap();
+        assert!((separated_length_mm - (3.0 * 370.0 + 4.0) / 300.0 * 25.4).abs() < 0.01);
+    }
+
+    #[test]
+    fn length_of_die_cut_labels_is_per_label() {
+        let job = PrintJob {
+            no_pages: 3,
+            ..PrintJob::new(blank_image(236, 236), Media::D24)
+        };
+        assert_eq!(job.estimated_length_mm().unwrap(), 24.0);
+    }
+
+    #[test]
+    fn two_color_layers_match_separate_images() {
+        let half = |dark: fn(u32) -> bool| {
+            let img = GrayImage::from_fn(696, 50, |x, _| Luma([if dark(x) { 0 } else { 255 }]));
+            DynamicImage::ImageLuma8(img)
+        };
+        let (black, red) = (half(|x| x < 300), half(|x| x >= 400));
+        let job = PrintJob::two_color(black.clone(), red.clone(), Media::C62R);
+        let raster_image = job.raster_image().unwrap();
+        let black_raster = RasterImage::from_image(black, Media::C62).unwrap();
+        let red_raster = RasterImage::from_image(red, Media::C62).unwrap();
+        assert_eq!(raster_image.black_layer(), black_raster.black_layer());
+        assert_eq!(raster_image.red_layer(), Some(red_raster.black_layer()));
+    }
+
+    #[test]
+    fn two_color_images_require_two_color_media() {
+        let job = PrintJob::two_color(blank_image(696, 50), blank_image(696, 50), Media::C62);
+        assert_eq!(job.compile(), Err(BQLError::TwoColorUnsupported));
+    }
+
+    #[test]
+    fn effective_margins_follow_alignment() {
+        let margins = |alignment| {
+            let job = PrintJob {
+                alignment: Some(alignment),
+                ..PrintJob::new(blank_image(200, 50), Media::C62)
+            };
+            job.effective_margins().unwrap()
+        };
+        assert_eq!(margins(Alignment::Center), (260, 260));
+        assert_eq!(margins(Alignment::Right), (508, 12));
+        for alignment in [Alignment::Left, Alignment::Center, Alignment::Right] {
+            let (left, right) = margins(alignment);
+            assert_eq!(left + 200 + right, 720);
+        }
+    }
+
+    /// Returns how often the initialize command occurs in the compiled data.
+    fn count_initialize(data: &[u8]) -> usize {
+        data.windows(2)
+            .filter(|window| window == &[0x1b, 0x40])
+            .count()
+    }
+
+    #[test]
+    fn preamble_is_repeated_per_page() {
+        let job = PrintJob {
+            no_pages: 3,
+            ..PrintJob::new(blank_image(696, 50), Media::C62)
+        };
+        assert_eq!(count_initialize(&job.clone().compile().unwrap()), 1);
+        let job = PrintJob {
+            preamble_per_page: true,
+            ..job
+        };
+        assert_eq!(count_initialize(&job.compile().unwrap()), 3);
+    }
+
+    #[test]
+    fn invalid_jobs_return_errors_instead_of_panicking() {
+        let unsupported = PrintJob::new(blank_image(696, 50), Media::C29);
+        assert_eq!(unsupported.compile(), Err(BQLError::UnsupportedMedia));
+        let zero_interval = PrintJob {
+            cut_behaviour: CutBehavior::CutEvery(0),
+            ..PrintJob::new(blank_image(696, 50), Media::C62)
+        };
+        assert_eq!(zero_interval.compile(), Err(BQLError::ZeroCutInterval));
+    }
+
+    #[test]
+    fn wire_size_matches_compiled_length() {
+        let job = PrintJob {
+            no_pages: 2,
+            ..PrintJob::new(blank_image(696, 100), Media::C62)
+        };
+        let wire_size = job.wire_size().unwrap();
+        assert_eq!(wire_size, job.clone().compile().unwrap().len());
+        let transmit_time = job.estimated_transmit_time(1000).unwrap();
+        assert!((transmit_time.as_secs_f64() - wire_size as f64 / 1000.0).abs() < 1e-6);
+    }
+
+    /// Returns the color power byte of every two-color raster graphics transfer in the compiled
+    /// data of a blank page.
+    fn color_power_bytes(job: PrintJob) -> Vec<u8> {
+        job.compile()
+            .unwrap()
+            .windows(3)
+            .filter(|window| window[0] == 0x77 && usize::from(window[2]) == RASTER_LINE_BYTES)
+            .map(|window| window[1])
+            .collect()
+    }
+
+    #[test]
+    fn two_color_energy_is_emitted_per_layer() {
+        let job = PrintJob::new(blank_image(696, 10), Media::C62R);
+        assert_eq!(color_power_bytes(job.clone()), [0x01, 0x02].repeat(10));
+        let swapped = PrintJob {
+            two_color_energy: TwoColorEnergy {
+                black: ColorPower::LowEnergy,
+                red: ColorPower::HighEnergy,
+            },
+            ..job
+        };
+        assert_eq!(color_power_bytes(swapped), [0x02, 0x01].repeat(10));
+    }
+
+    #[test]
+    fn color_passes_are_monochrome() {
+        let image = RgbImage::from_fn(696, 10, |x, _| match x {
+            0..300 => Rgb([0, 0, 0]),
+            300..400 => Rgb([255, 255, 255]),
+            _ => Rgb([255, 0, 0]),
+        });
+        let job = PrintJob {
+            dither_mode: DitherMode::Threshold(u8::MAX),
+            ..PrintJob::new(DynamicImage::ImageRgb8(image), Media::C62R)
+        };
+        let (black_job, red_job) = job.into_color_passes().unwrap();
+        for (job, dots) in [(black_job, 300 * 10), (red_job, 296 * 10)] {
+            assert_eq!(job.media, Media::C62);
+            let raster_image = job.raster_image().unwrap();
+            assert_eq!(raster_image.red_layer(), None);
+            assert_eq!(raster_image.dot_counts(), (dots, 0));
+        }
+    }
+
+    /// Returns the margin amount of the first specify margin amount command in the compiled data.
+    fn margin_amount(job: PrintJob) -> u16 {
+        let data = job.compile().unwrap();
+        let position = data
+            .windows(3)
+            .position(|window| window == [0x1b, 0x69, 0x64])
+            .unwrap();
+        u16::from_le_bytes([data[position + 3], data[position + 4]])
+    }
+
+    #[test]
+    fn feed_margin_in_millimeters_is_converted_to_dots() {
+        let job = PrintJob {
+            feed_margin: Some(FeedMargin::Millimeters(3)),
+            ..PrintJob::new(blank_image(696, 50), Media::C62)
+        };
+        assert_eq!(margin_amount(job.clone()), 35);
+        let high_dpi_job = PrintJob {
+            high_dpi_upscale: true,
+            ..job
+        };
+        assert_eq!(margin_amount(high_dpi_job), 71);
+    }
+
+    #[test]
+    fn feed_margin_out_of_range_is_rejected() {
+        let job = PrintJob {
+            feed_margin: Some(FeedMargin::Dots(20)),
+            ..PrintJob::new(blank_image(696, 50), Media::C62)
+        };
+        assert_eq!(job.compile(), Err(BQLError::InvalidFeedMargin));
+    }
+
+    #[test]
+    fn default_feed_margin_is_valid_in_high_dpi_mode() {
+        let high_dpi_job = PrintJob {
+            high_dpi_upscale: true,
+            ..PrintJob::new(blank_image(696, 50), Media::C62)
+        };
+        let default_margin = margin_amount(high_dpi_job.clone());
+        assert_eq!(default_margin, 70);
+        let explicit_job = PrintJob {
+            feed_margin: Some(FeedMargin::Dots(default_margin)),
+            ..high_dpi_job.clone()
+        };
+        assert_eq!(explicit_job.compile(), high_dpi_job.compile());
+        let too_small_job = PrintJob {
+            feed_margin: Some(FeedMargin::Dots(35)),
+            high_dpi: true,
+            ..PrintJob::new(blank_image(696, 100), Media::C62)
+        };
+        assert_eq!(too_small_job.compile(), Err(BQLError::InvalidFeedMargin));
+    }
+
+    #[test]
+    fn with_image_keeps_settings() {
+        let job = PrintJob {
+            no_pages: 3,
+            cut_behaviour: CutBehavior::CutEach,
+            quality_priority: true,
+            feed_margin: Some(FeedMargin::Millimeters(5)),
+            ..PrintJob::new(blank_image(696, 50), Media::C62)
+        };
+        let black = DynamicImage::ImageLuma8(GrayImage::new(696, 50));
+        let new_job = job.with_image(black.clone()).unwrap();
+        assert_eq!(
+            new_job,
+            PrintJob {
+                image: black,
+                ..job.clone()
+            }
+        );
+        assert_ne!(new_job.raster_image(), job.raster_image());
+        assert_eq!(
+            job.with_image(blank_image(600, 50)),
+            Err(BQLError::DimensionMismatch)
+        );
+    }
+
+    #[test]
+    fn blank_lines_use_zero_raster_graphics_if_enabled() {
+        let job = PrintJob::new(blank_image(696, 10), Media::C62);
+        let optimized_job = PrintJob {
+            optimize_blank_lines: true,
+            ..job.clone()
+        };
+        let count = |job: &PrintJob, command: &str| {
+            let commands = job.describe().unwrap();
+            commands.iter().filter(|c| c.starts_with(command)).count()
+        };
+        assert_eq!(count(&job, "RasterGraphicsTransfer"), 10);
+        assert_eq!(count(&job, "ZeroRasterGraphics"), 0);
+        assert_eq!(count(&optimized_job, "RasterGraphicsTransfer"), 0);
+        assert_eq!(count(&optimized_job, "ZeroRasterGraphics"), 10);
+        let (data, optimized_data) = (job.compile().unwrap(), optimized_job.compile().unwrap());
+        assert_eq!(
+            data.len() - optimized_data.len(),
+            10 * (3 + RASTER_LINE_BYTES - 1)
+        );
+        assert_eq!(
+            optimized_data.iter().filter(|&&byte| byte == 0x5a).count(),
+            10
+        );
+    }
+
+    #[test]
+    fn dimension_tolerance_pads_near_fitting_images() {
+        let job = PrintJob::new(blank_image(236, 234), Media::D24);
+        assert_eq!(job.clone().compile(), Err(BQLError::DimensionMismatch));
+        let tolerant_job = PrintJob {
+            dimension_tolerance: 4,
+            ..job
+        };
+        assert_eq!(tolerant_job.raster_image().unwrap().no_lines(), 236);
+    }
+
+    #[test]
+    fn warnings_for_black_only_two_color_job() {
+        let image = GrayImage::from_fn(696, 50, |x, _| Luma([if x < 300 { 0 } else { 255 }]));
+        let job = PrintJob {
+            compressed: true,
+            ..PrintJob::new(DynamicImage::ImageLuma8(image), Media::C62R)
+        };
+        let (_, warnings) = job.compile_with_warnings().unwrap();
+        assert_eq!(
+            warnings,
+            [Warning::CompressionUnsupported, Warning::NoRedContent]
+        );
+        let (_, warnings) = PrintJob::new(blank_image(696, 50), Media::C62)
+            .compile_with_warnings()
+            .unwrap();
+        assert_eq!(warnings, [Warning::BlankPage]);
+    }
+
+    #[test]
+    fn compile_to_matches_compile() {
+        let job = PrintJob {
+            no_pages: 3,
+            ..PrintJob::solid_fill(Media::C62R, false).unwrap()
+        };
+        let mut written = Vec::new();
+        job.clone().compile_to(&mut written).unwrap();
+        assert_eq!(written, job.compile().unwrap());
+        let invalid_job = PrintJob::new(blank_image(600, 50), Media::C62);
+        let err = invalid_job.compile_to(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn cut_mask_overrides_cut_behavior() {
+        let job = PrintJob {
+            no_pages: 4,
+            cut_behaviour: CutBehavior::CutEach,
+            cut_mask: Some(vec![true, false, true]),
+            ..PrintJob::new(blank_image(696, 10), Media::C62)
+        };
+        let (_, pages) = job.clone().compile_pages().unwrap();
+        let auto_cut = pages
+            .iter()
+            .map(|page| page.windows(4).any(|w| w == [0x1b, 0x69, 0x4d, 0x40]))
+            .collect::<Vec<_>>();
+        assert_eq!(auto_cut, [true, false, true, false]);
+        // The last page is beyond the mask, so the job does not end with a cut
+        assert_eq!(job.cut_settings(3), (false, None, false));
+    }
+
+    /// Returns the argument bytes of the first command starting with `prefix` in the compiled
+    /// data.
+    fn command_args(data: &[u8], prefix: [u8; 3], len: usize) -> &[u8] {
+        let position = data.windows(3).position(|w| w == prefix).unwrap();
+        &data[position + 3..position + 3 + len]
+    }
+
+    #[test]
+    fn high_dpi_upscale_doubles_lines() {
+        let job = PrintJob {
+            high_dpi_upscale: true,
+            ..PrintJob::new(blank_image(696, 50), Media::C62)
+        };
+        assert_eq!(job.raster_image().unwrap().no_lines(), 100);
+        let data = job.compile().unwrap();
+        let print_information = command_args(&data, [0x1b, 0x69, 0x7a], 10);
+        assert_eq!(print_information[4..8], 100u32.to_le_bytes());
+        let expanded_mode = command_args(&data, [0x1b, 0x69, 0x4b], 1);
+        assert_eq!(expanded_mode[0] & 0x40, 0x40);
+    }
+
+    #[test]
+    fn describe_lists_commands_in_order() {
+        let job = PrintJob::new(blank_image(696, 2), Media::C62);
+        assert_eq!(
+            job.describe().unwrap(),
+            [
+                "Invalidate",
+                "Initialize",
+                "SwitchDynamicCommandMode(Raster)",
+                "SwitchAutomaticStatusNotificationMode(notify: false)",
+                "PrintInformation(media_type: Continuous, width_mm: 62, quality_priority: false, \
+                 recovery_on: true, no_lines: 2, first_page: true)",
+                "VariousMode(auto_cut: true)",
+                "ExpandedMode(two_color: false, cut_at_end: true, high_dpi: false)",
+                "SpecifyMarginAmount(35)",
+                "SelectCompressionMode(tiff: false)",
+                "RasterGraphicsTransfer(90 bytes)",
+                "RasterGraphicsTransfer(90 bytes)",
+                "PrintWithFeed",
+            ]
+        );
+    }
+
+    #[test]
+    fn last_page_is_not_fed_if_disabled() {
+        let job = PrintJob::new(blank_image(696, 10), Media::C62);
+        assert_eq!(job.clone().compile().unwrap().last(), Some(&0x1a));
+        let job = PrintJob {
+            feed_at_end: false,
+            ..job
+        };
+        assert_eq!(job.compile().unwrap().last(), Some(&0x0c));
+    }
+
+    #[test]
+    fn feed_margin_is_emitted_for_continuous_media_only() {
+        let job = PrintJob {
+            feed_margin: Some(FeedMargin::Dots(100)),
+            ..PrintJob::new(blank_image(696, 50), Media::C62)
+        };
+        assert_eq!(margin_amount(job), 100);
+        let die_cut_job = PrintJob {
+            feed_margin: Some(FeedMargin::Dots(100)),
+            ..PrintJob::new(blank_image(236, 236), Media::D24)
+        };
+        assert_eq!(margin_amount(die_cut_job), 0);
+        for dots in [34, 1501] {
+            let job = PrintJob {
+                feed_margin: Some(FeedMargin::Dots(dots)),
+                ..PrintJob::new(blank_image(696, 50), Media::C62)
+            };
+            assert_eq!(job.compile(), Err(BQLError::InvalidFeedMargin));
+        }
+    }
+
+    #[test]
+    fn pages_concatenate_to_compiled_data() {
+        for preamble_per_page in [false, true] {
+            let job = PrintJob {
+                no_pages: 3,
+                preamble_per_page,
+                separator: Separator::Dotted,
+                ..PrintJob::new(blank_image(696, 50), Media::C62)
+            };
+            let (preamble, pages) = job.clone().compile_pages().unwrap();
+            assert_eq!(pages.len(), 3);
+            assert_eq!([preamble, pages.concat()].concat(), job.compile().unwrap());
+        }
+    }
+
+    #[test]
+    fn preview_covers_full_raster_lines() {
+        let job = PrintJob {
+            no_pages: 2,
+            ..PrintJob::new(blank_image(696, 50), Media::C62)
+        };
+        let previews = job.preview().unwrap();
+        assert_eq!(previews.len(), 2);
+        assert!(previews
+            .iter()
+            .all(|preview| preview.dimensions() == (720, 50)));
+        let die_cut_job = PrintJob::new(blank_image(236, 236), Media::D24);
+        assert_eq!(die_cut_job.preview().unwrap()[0].dimensions(), (720, 236));
+    }
+
+    #[test]
+    fn color_preview_places_both_layers() {
+        let image = RgbImage::from_fn(696, 20, |x, y| match (x, y) {
+            (0..300, 0..10) => Rgb([0, 0, 0]),
+            (400.., 10..) => Rgb([255, 0, 0]),
+            _ => Rgb([255, 255, 255]),
+        });
+        let job = PrintJob {
+            dither_mode: DitherMode::Threshold(u8::MAX),
+            ..PrintJob::new(DynamicImage::ImageRgb8(image), Media::C62R)
+        };
+        let preview = job.preview_color().unwrap()[0].to_rgb8();
+        // The image starts after the 12 unprintable dots of the raster line
+        assert_eq!(preview.get_pixel(12 + 100, 5), &Rgb([0, 0, 0]));
+        assert_eq!(preview.get_pixel(12 + 500, 15), &Rgb([255, 0, 0]));
+        assert_eq!(preview.get_pixel(12 + 500, 5), &Rgb([255, 255, 255]));
+        assert_eq!(preview.get_pixel(12 + 100, 15), &Rgb([255, 255, 255]));
+    }
+
+    #[test]
+    fn rotation_fits_landscape_images() {
+        let landscape = GrayImage::from_fn(300, 696, |x, _| Luma([if x < 10 { 0 } else { 255 }]));
+        let landscape = DynamicImage::ImageLuma8(landscape);
+        let job = PrintJob::new(landscape.clone(), Media::C62);
+        assert_eq!(job.clone().compile(), Err(BQLError::DimensionMismatch));
+        for (rotation, rotated) in [
+            (Rotation::Cw90, landscape.rotate90()),
+            (Rotation::Ccw90, landscape.rotate270()),
+        ] {
+            let rotated_job = PrintJob {
+                rotation,
+                ..job.clone()
+            };
+            let raster_image = rotated_job.raster_image().unwrap();
+            assert_eq!(raster_image.no_lines(), 300);
+            assert_eq!(
+                Ok(raster_image),
+                RasterImage::from_image(rotated, Media::C62)
+            );
+        }
+    }
+
+    #[test]
+    fn rebuild_for_rasterizes_for_new_media() {
+        let job = PrintJob {
+            no_pages: 2,
+            ..PrintJob::new(blank_image(696, 300), Media::C62)
+        };
+        // C29 has no media settings yet, so the job is rebuilt for D24 instead
+        assert_eq!(job.rebuild_for(Media::C29), Err(BQLError::UnsupportedMedia));
+        let rebuilt = job.rebuild_for(Media::D24).unwrap();
+        assert_eq!(rebuilt.no_pages, 2);
+        assert_eq!(rebuilt.effective_margins().unwrap(), (442, 42));
+        assert_eq!(rebuilt.raster_image().unwrap().no_lines(), 236);
+        let two_color_job =
+            PrintJob::two_color(blank_image(696, 50), blank_image(696, 50), Media::C62R);
+        assert_eq!(
+            two_color_job.rebuild_for(Media::C62),
+            Err(BQLError::TwoColorUnsupported)
+        );
+    }
+
+    /// Returns the amount of raster lines announced by the print information command of a page.
+    fn page_lines(page: &[u8]) -> u32 {
+        let print_information = command_args(page, [0x1b, 0x69, 0x7a], 10);
+        u32::from_le_bytes(print_information[4..8].try_into().unwrap())
+    }
+
+    #[test]
+    fn separator_adds_lines_between_pages() {
+        let job = PrintJob {
+            no_pages: 3,
+            separator: Separator::Solid,
+            ..PrintJob::new(blank_image(696, 50), Media::C62)
+        };
+        let (_, pages) = job.clone().compile_pages().unwrap();
+        let no_lines = pages
+            .iter()
+            .map(|page| page_lines(page))
+            .collect::<Vec<_>>();
+        assert_eq!(no_lines, [52, 52, 50]);
+        let transfers = job
+            .describe()
+            .unwrap()
+            .into_iter()
+            .filter(|command| command.starts_with("RasterGraphicsTransfer"))
+            .count();
+        assert_eq!(transfers, 3 * 50 + 2 * 2);
+        let die_cut_job = PrintJob {
+            no_pages: 3,
+            separator: Separator::Solid,
+            ..PrintJob::new(blank_image(236, 236), Media::D24)
+        };
+        let (_, pages) = die_cut_job.compile_pages().unwrap();
+        assert!(pages.iter().all(|page| page_lines(page) == 236));
+    }
+
+    #[test]
+    fn trim_blank_removes_trailing_blank_lines() {
+        let image = GrayImage::from_fn(696, 1000, |x, y| {
+            Luma([if y < 600 && x % 2 == 0 { 0 } else { 255 }])
+        });
+        let job = PrintJob {
+            trim_blank: Some(20),
+            ..PrintJob::new(DynamicImage::ImageLuma8(image), Media::C62)
+        };
+        assert_eq!(job.raster_image().unwrap().no_lines(), 620);
+        let untrimmed_job = PrintJob {
+            trim_blank: None,
+            ..job
+        };
+        assert_eq!(untrimmed_job.raster_image().unwrap().no_lines(), 1000);
+        let die_cut_job = PrintJob {
+            trim_blank: Some(0),
+            ..PrintJob::new(blank_image(236, 236), Media::D24)
+        };
+        assert_eq!(die_cut_job.raster_image().unwrap().no_lines(), 236);
+    }
+
+    #[test]
+    fn compile_raster_requires_matching_resolution() {
+        let upscaled_job = PrintJob {
+            high_dpi_upscale: true,
+            ..PrintJob::new(blank_image(696, 50), Media::C62)
+        };
+        let raster_image = upscaled_job.raster_image().unwrap();
+        assert!(raster_image.high_dpi());
+        assert_eq!(
+            upscaled_job.compile_raster(&raster_image),
+            upscaled_job.clone().compile()
+        );
+        let high_dpi_job = PrintJob {
+            high_dpi: true,
+            ..PrintJob::new(blank_image(696, 100), Media::C62)
+        };
+        assert!(high_dpi_job.compile_raster(&raster_image).is_ok());
+        let job = PrintJob::new(blank_image(696, 100), Media::C62);
+        assert_eq!(
+            job.compile_raster(&raster_image),
+            Err(BQLError::DimensionMismatch)
+        );
+        let raster_image = job.raster_image().unwrap();
+        assert_eq!(
+            upscaled_job.compile_raster(&raster_image),
+            Err(BQLError::DimensionMismatch)
+        );
+        let other_media_job = PrintJob::new(blank_image(696, 100), Media::C62R);
+        assert_eq!(
+            other_media_job.compile_raster(&raster_image),
+            Err(BQLError::DimensionMismatch)
+        );
     }
 }
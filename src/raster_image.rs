@@ -1,17 +1,51 @@
+//! This module provides the rasterized form of a label image.
+use std::borrow::Cow;
+
 use image::{
-    imageops::{self, BiLevel},
-    DynamicImage, GenericImageView, GrayImage, ImageBuffer, Rgb, RgbImage,
+    imageops::{self, BiLevel, FilterType},
+    DynamicImage, GenericImage, GenericImageView, GrayImage, ImageBuffer, Luma, LumaA, Rgb,
+    RgbImage, Rgba, RgbaImage,
 };
 use itertools::Itertools;
 
 use crate::{
     error::BQLError,
-    media::{MediaSettings, MediaType},
+    media::{Media, MediaSettings, MediaType, RASTER_LINE_BYTES, RASTER_LINE_DOTS},
+    printjob::{Alignment, DitherMode, PrintJob, Rotation, Separator, TwoColorThresholds},
 };
 
-type RasterLayer = Vec<[u8; 90]>;
+type RasterLayer = Vec<[u8; RASTER_LINE_BYTES]>;
+
+/// An image converted to the raster lines sent to the printer for a specific media.
+///
+/// Rasterizing is the most expensive part of compiling a print job. A [RasterImage] can be
+/// created once and printed many times using [PrintJob::compile_raster]:
+///
+/// ```no_run
+/// use brother_ql::{media::Media, printjob::PrintJob, raster_image::RasterImage};
+///
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let img = image::open("label.png")?;
+/// let raster = RasterImage::from_image(img, Media::C62)?;
+/// for no_pages in [1, 5, 10] {
+///     let job = PrintJob {
+///         no_pages,
+///         ..PrintJob::new(Default::default(), Media::C62)
+///     };
+///     let data = job.compile_raster(&raster)?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RasterImage {
+    media: Media,
+    high_dpi: bool,
+    pub(crate) layers: RasterLayers,
+}
 
-pub(crate) enum RasterImage {
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub(crate) enum RasterLayers {
     Monochrome {
         black_layer: RasterLayer,
     },
@@ -22,62 +56,454 @@ pub(crate) enum RasterImage {
 }
 
 impl RasterImage {
-    pub(crate) fn new(img: DynamicImage, media_settings: &MediaSettings) -> Result<Self, BQLError> {
-        let (width, height) = img.dimensions();
-        // Always check width, for die-cut labels, also check height
-        if media_settings.width_dots != width {
-            return Err(BQLError::DimensionMismatch);
-        }
-        if let MediaType::DieCut { length_dots, .. } = media_settings.media_type {
-            if length_dots != height {
-                return Err(BQLError::DimensionMismatch);
+    /// Rasterizes an image for the given media using the default settings of [PrintJob::new].
+    ///
+    /// Use [PrintJob::raster_image] to rasterize with other settings. Returns an error if the
+    /// media is not supported or the image does not match the media dimensions.
+    pub fn from_image(image: DynamicImage, media: Media) -> Result<Self, BQLError> {
+        PrintJob::new(image, media).raster_image()
+    }
+
+    pub(crate) fn new(job: &PrintJob, media_settings: &MediaSettings) -> Result<Self, BQLError> {
+        let mut layers = RasterLayers::rasterize(job, media_settings)?;
+        if let (Some(keep), MediaType::Continuous) = (job.trim_blank, media_settings.media_type) {
+            layers.trim_bottom(keep as usize);
+        }
+        if job.high_dpi_upscale {
+            layers.for_each_layer(|layer| {
+                *layer = layer.iter().flat_map(|&line| [line, line]).collect();
+            });
+        }
+        Ok(Self {
+            media: job.media,
+            high_dpi: job.high_dpi || job.high_dpi_upscale,
+            layers,
+        })
+    }
+
+    /// Returns the media this image was rasterized for.
+    pub fn media(&self) -> Media {
+        self.media
+    }
+
+    /// Returns whether this image was rasterized for high resolution printing, see
+    /// [high_dpi][PrintJob::high_dpi].
+    pub fn high_dpi(&self) -> bool {
+        self.high_dpi
+    }
+
+    /// Returns the raster lines printed in black, in the order they are sent to the printer.
+    ///
+    /// Every line packs eight dots per byte, starting with the most significant bit. A set bit
+    /// is printed.
+    pub fn black_layer(&self) -> &[[u8; RASTER_LINE_BYTES]] {
+        match &self.layers {
+            RasterLayers::Monochrome { black_layer }
+            | RasterLayers::TwoColor { black_layer, .. } => black_layer,
+        }
+    }
+
+    /// Returns the raster lines printed in red, or [None] for monochrome media. The lines are
+    /// packed like the [black layer][RasterImage::black_layer].
+    pub fn red_layer(&self) -> Option<&[[u8; RASTER_LINE_BYTES]]> {
+        match &self.layers {
+            RasterLayers::Monochrome { .. } => None,
+            RasterLayers::TwoColor { red_layer, .. } => Some(red_layer),
+        }
+    }
+
+    /// Returns the amount of printed dots in the black and red layer.
+    pub(crate) fn dot_counts(&self) -> (usize, usize) {
+        let count = |layer: &[[u8; RASTER_LINE_BYTES]]| {
+            layer
+                .iter()
+                .flatten()
+                .map(|byte| byte.count_ones() as usize)
+                .sum()
+        };
+        (count(self.black_layer()), self.red_layer().map_or(0, count))
+    }
+
+    /// Returns the amount of raster lines per layer.
+    pub(crate) fn no_lines(&self) -> u32 {
+        self.black_layer().len() as u32
+    }
+
+    /// Reconstructs a grayscale image of what is printed, with every printed dot in black.
+    ///
+    /// The image is as wide as a full raster line, including the unprintable margins, and has
+    /// one row per raster line, so high-DPI images appear stretched.
+    pub fn to_image(&self) -> DynamicImage {
+        let black = self.black_layer();
+        let red = self.red_layer();
+        let img = GrayImage::from_fn(RASTER_LINE_DOTS, self.no_lines(), |x, y| {
+            let printed = is_dot_set(black, x, y) || red.is_some_and(|red| is_dot_set(red, x, y));
+            Luma([if printed { 0 } else { 255 }])
+        });
+        DynamicImage::ImageLuma8(img)
+    }
+
+    /// Like [to_image][RasterImage::to_image], but renders the dots of the red layer in red.
+    /// Dots set in both layers are rendered in black.
+    pub fn to_color_image(&self) -> DynamicImage {
+        let black = self.black_layer();
+        let red = self.red_layer();
+        let img = RgbImage::from_fn(RASTER_LINE_DOTS, self.no_lines(), |x, y| {
+            if is_dot_set(black, x, y) {
+                Rgb([0, 0, 0])
+            } else if red.is_some_and(|red| is_dot_set(red, x, y)) {
+                Rgb([255, 0, 0])
+            } else {
+                Rgb([255, 255, 255])
+            }
+        });
+        DynamicImage::ImageRgb8(img)
+    }
+}
+
+/// Returns whether the dot at the given position of the reconstructed image is set in the layer.
+fn is_dot_set(layer: &[[u8; RASTER_LINE_BYTES]], x: u32, y: u32) -> bool {
+    // The raster lines are stored in reverse order
+    let line = &layer[layer.len() - 1 - y as usize];
+    line[x as usize / 8] & (1 << (7 - x % 8)) != 0
+}
+
+impl RasterLayers {
+    fn rasterize(job: &PrintJob, media_settings: &MediaSettings) -> Result<Self, BQLError> {
+        let img = prepare_image(&job.image, job, media_settings)?;
+        if let Some(red_img) = &job.red_image {
+            if !media_settings.color {
+                return Err(BQLError::TwoColorUnsupported);
             }
+            let red_img = prepare_image(red_img, job, media_settings)?;
+            return Ok(Self::TwoColor {
+                black_layer: mask_to_raster_layer(create_mask(
+                    &img,
+                    job,
+                    media_settings,
+                    is_not_white,
+                )),
+                red_layer: mask_to_raster_layer(create_mask(
+                    &red_img,
+                    job,
+                    media_settings,
+                    is_not_white,
+                )),
+            });
         }
         Ok(if media_settings.color {
+            let thresholds = job.two_color_thresholds;
             Self::TwoColor {
                 black_layer: mask_to_raster_layer(create_mask(
                     &img,
-                    media_settings.left_margin,
-                    |r, g, b| r == g && r == b && r < 200,
+                    job,
+                    media_settings,
+                    |r, g, b| thresholds.is_black(r, g, b),
                 )),
-                red_layer: mask_to_raster_layer(create_mask(
+                red_layer: mask_to_raster_layer(create_filter_mask(
                     &img,
-                    media_settings.left_margin,
-                    |r, g, b| r > 100 && r > b && r > g,
+                    job,
+                    media_settings,
+                    |r, g, b| thresholds.is_red(r, g, b),
                 )),
             }
         } else {
             Self::Monochrome {
                 black_layer: mask_to_raster_layer(create_mask(
                     &img,
-                    media_settings.left_margin,
-                    |r, g, b| !(r == b && r == g && r == 255),
+                    job,
+                    media_settings,
+                    is_not_white,
                 )),
             }
         })
     }
+
+    /// Removes blank lines at the bottom of the image, keeping `keep` of them. At least one line
+    /// is always kept.
+    fn trim_bottom(&mut self, keep: usize) {
+        // The lines are stored in reverse order, so the bottom of the image comes first
+        let mut blank = usize::MAX;
+        self.for_each_layer(|layer| {
+            let blank_lines = layer
+                .iter()
+                .take_while(|line| line.iter().all(|&byte| byte == 0))
+                .count();
+            blank = blank.min(blank_lines).min(layer.len().saturating_sub(1));
+        });
+        let trim = blank.saturating_sub(keep);
+        self.for_each_layer(|layer| {
+            layer.drain(..trim);
+        });
+    }
+
+    fn for_each_layer(&mut self, mut f: impl FnMut(&mut RasterLayer)) {
+        match self {
+            Self::Monochrome { black_layer } => f(black_layer),
+            Self::TwoColor {
+                black_layer,
+                red_layer,
+            } => {
+                f(black_layer);
+                f(red_layer);
+            }
+        }
+    }
+}
+
+/// Applies all image transformations of the print job and makes sure the result fits the media.
+fn prepare_image<'a>(
+    img: &'a DynamicImage,
+    job: &PrintJob,
+    media_settings: &MediaSettings,
+) -> Result<Cow<'a, DynamicImage>, BQLError> {
+    let mut img = rotate(Cow::Borrowed(img), job.rotation);
+    if job.auto_resize {
+        img = resize_to_media(img, media_settings)?;
+    } else {
+        check_dimensions(&img, job, media_settings)?;
+    }
+    // Adjust before fitting, so the padding stays white
+    img = adjust_tones(img, job);
+    img = flatten_alpha(img, job.background);
+    img = fit_to_media(img, job, media_settings);
+    if job.circular_mask.unwrap_or(job.media.is_round()) {
+        img = mask_circle(img, job, media_settings);
+    }
+    Ok(img)
+}
+
+/// Composites an image with an alpha channel over the background color, so fully transparent
+/// pixels get the background color.
+fn flatten_alpha(img: Cow<'_, DynamicImage>, background: Rgb<u8>) -> Cow<'_, DynamicImage> {
+    if !img.color().has_alpha() {
+        return img;
+    }
+    let Rgb([r, g, b]) = background;
+    if let (DynamicImage::ImageLumaA8(gray), true) = (img.as_ref(), r == g && g == b) {
+        // Keep grayscale images grayscale, see create_mask
+        let flattened = GrayImage::from_fn(gray.width(), gray.height(), |x, y| {
+            let LumaA([l, a]) = *gray.get_pixel(x, y);
+            Luma([blend(l, r, a)])
+        });
+        return Cow::Owned(DynamicImage::ImageLuma8(flattened));
+    }
+    let rgba = img.to_rgba8();
+    let flattened = RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let Rgba([r, g, b, a]) = *rgba.get_pixel(x, y);
+        Rgb([
+            blend(r, background[0], a),
+            blend(g, background[1], a),
+            blend(b, background[2], a),
+        ])
+    });
+    Cow::Owned(DynamicImage::ImageRgb8(flattened))
+}
+
+/// Composites a foreground channel with the given alpha over a background channel.
+fn blend(fg: u8, bg: u8, alpha: u8) -> u8 {
+    let (fg, bg, alpha) = (u32::from(fg), u32::from(bg), u32::from(alpha));
+    ((fg * alpha + bg * (255 - alpha) + 127) / 255) as u8
+}
+
+/// Makes all pixels outside of the circle inscribed in a die-cut label white.
+fn mask_circle<'a>(
+    img: Cow<'a, DynamicImage>,
+    job: &PrintJob,
+    media_settings: &MediaSettings,
+) -> Cow<'a, DynamicImage> {
+    let MediaType::DieCut { length_dots, .. } = media_settings.media_type else {
+        return img;
+    };
+    // The image may be narrower than the label, so the center is relative to the label. Raster
+    // lines are mirrored, so the alignment offset is counted from the right edge of the image.
+    let offset = f64::from(alignment_offset(img.width(), media_settings, job.alignment));
+    let center_x = offset + f64::from(img.width()) - f64::from(media_settings.width_dots) / 2.0;
+    let center_y = f64::from(length_dots) / 2.0;
+    let radius = f64::from(media_settings.width_dots.min(length_dots)) / 2.0;
+    let mut masked = img.into_owned();
+    (0..masked.height())
+        .cartesian_product(0..masked.width())
+        .filter(|&(y, x)| {
+            let dx = f64::from(x) + 0.5 - center_x;
+            let dy = f64::from(y) + 0.5 - center_y;
+            dx * dx + dy * dy > radius * radius
+        })
+        .for_each(|(y, x)| masked.put_pixel(x, y, Rgba([255; 4])));
+    Cow::Owned(masked)
+}
+
+/// Applies the gamma correction, brightness and contrast adjustments of the print job, in this
+/// order.
+fn adjust_tones<'a>(mut img: Cow<'a, DynamicImage>, job: &PrintJob) -> Cow<'a, DynamicImage> {
+    if job.gamma != 1.0 {
+        let lut: [u8; 256] = std::array::from_fn(|v| {
+            (255.0 * (v as f32 / 255.0).powf(1.0 / job.gamma)).round() as u8
+        });
+        img = Cow::Owned(match img.as_ref() {
+            // Keep grayscale images grayscale, see create_mask
+            DynamicImage::ImageLuma8(gray) => {
+                let mut adjusted = gray.clone();
+                adjusted
+                    .pixels_mut()
+                    .for_each(|px| px.0[0] = lut[px.0[0] as usize]);
+                DynamicImage::ImageLuma8(adjusted)
+            }
+            DynamicImage::ImageLumaA8(gray) => {
+                let mut adjusted = gray.clone();
+                adjusted
+                    .pixels_mut()
+                    .for_each(|px| px.0[0] = lut[px.0[0] as usize]);
+                DynamicImage::ImageLumaA8(adjusted)
+            }
+            _ => {
+                let mut adjusted = img.to_rgba8();
+                adjusted.pixels_mut().for_each(|px| {
+                    let [r, g, b, a] = px.0;
+                    px.0 = [lut[r as usize], lut[g as usize], lut[b as usize], a];
+                });
+                DynamicImage::ImageRgba8(adjusted)
+            }
+        });
+    }
+    if job.brightness != 0 {
+        img = Cow::Owned(img.brighten(job.brightness));
+    }
+    if job.contrast != 0.0 {
+        img = Cow::Owned(img.adjust_contrast(job.contrast));
+    }
+    img
+}
+
+/// Rotates the image as requested by the print job.
+pub(crate) fn rotate(img: Cow<'_, DynamicImage>, rotation: Rotation) -> Cow<'_, DynamicImage> {
+    match rotation {
+        Rotation::None => img,
+        Rotation::Cw90 => Cow::Owned(img.rotate90()),
+        Rotation::Ccw90 => Cow::Owned(img.rotate270()),
+        Rotation::Rot180 => Cow::Owned(img.rotate180()),
+    }
+}
+
+/// Returns the size an image must have to be printed on the media. For continuous media, the
+/// height of the given image is used. If an alignment is set, narrower images keep their width.
+fn target_dimensions(
+    img: &DynamicImage,
+    job: &PrintJob,
+    media_settings: &MediaSettings,
+) -> (u32, u32) {
+    let width = match job.alignment {
+        Some(_) => img.width().min(media_settings.width_dots),
+        None => media_settings.width_dots,
+    };
+    match media_settings.media_type {
+        MediaType::Continuous => (width, img.height()),
+        MediaType::DieCut { length_dots, .. } => (width, length_dots),
+    }
+}
+
+/// Checks whether the image matches the media dimensions with at most `tolerance` dots of
+/// difference in each dimension.
+pub(crate) fn check_dimensions(
+    img: &DynamicImage,
+    job: &PrintJob,
+    media_settings: &MediaSettings,
+) -> Result<(), BQLError> {
+    let (width, height) = img.dimensions();
+    // Always check width, for die-cut labels, also check height
+    let (target_width, target_height) = target_dimensions(img, job, media_settings);
+    let tolerance = job.dimension_tolerance;
+    if width.abs_diff(target_width) > tolerance || height.abs_diff(target_height) > tolerance {
+        return Err(BQLError::DimensionMismatch);
+    }
+    Ok(())
+}
+
+/// Scales the image to the printable width while preserving its aspect ratio. On die-cut media,
+/// the image is scaled down further if necessary, so it does not exceed the label length.
+/// Returns an error for empty images, which can not be scaled.
+fn resize_to_media<'a>(
+    img: Cow<'a, DynamicImage>,
+    media_settings: &MediaSettings,
+) -> Result<Cow<'a, DynamicImage>, BQLError> {
+    let (width, height) = img.dimensions();
+    if width == 0 || height == 0 {
+        return Err(BQLError::DimensionMismatch);
+    }
+    let mut scale = f64::from(media_settings.width_dots) / f64::from(width);
+    if let MediaType::DieCut { length_dots, .. } = media_settings.media_type {
+        scale = scale.min(f64::from(length_dots) / f64::from(height));
+    }
+    let scaled_width = ((f64::from(width) * scale).round() as u32).max(1);
+    let scaled_height = ((f64::from(height) * scale).round() as u32).max(1);
+    if (scaled_width, scaled_height) == (width, height) {
+        return Ok(img);
+    }
+    Ok(Cow::Owned(img.resize_exact(
+        scaled_width,
+        scaled_height,
+        FilterType::Lanczos3,
+    )))
+}
+
+/// Pads the image with white or crops it, keeping it centered, so it matches the media
+/// dimensions exactly.
+fn fit_to_media<'a>(
+    img: Cow<'a, DynamicImage>,
+    job: &PrintJob,
+    media_settings: &MediaSettings,
+) -> Cow<'a, DynamicImage> {
+    let (width, height) = img.dimensions();
+    let (target_width, target_height) = target_dimensions(&img, job, media_settings);
+    if (width, height) == (target_width, target_height) {
+        return img;
+    }
+    let x = (i64::from(target_width) - i64::from(width)) / 2;
+    let y = (i64::from(target_height) - i64::from(height)) / 2;
+    Cow::Owned(match img.as_ref() {
+        // Keep grayscale images grayscale, see create_mask
+        DynamicImage::ImageLuma8(gray) => {
+            let mut fitted = GrayImage::from_pixel(target_width, target_height, Luma([255]));
+            imageops::replace(&mut fitted, gray, x, y);
+            DynamicImage::ImageLuma8(fitted)
+        }
+        _ => {
+            let mut fitted = RgbaImage::from_pixel(target_width, target_height, Rgba([255; 4]));
+            imageops::overlay(&mut fitted, img.as_ref(), x, y);
+            DynamicImage::ImageRgba8(fitted)
+        }
+    })
+}
+
+/// Splits an image into the parts printed in black and red on two-color media. Pixels that are
+/// not part of a layer are white.
+pub(crate) fn separate_colors(
+    img: &DynamicImage,
+    thresholds: TwoColorThresholds,
+) -> (DynamicImage, DynamicImage) {
+    (
+        DynamicImage::ImageRgb8(filter_pixels(img, |r, g, b| thresholds.is_black(r, g, b))),
+        DynamicImage::ImageRgb8(filter_pixels(img, |r, g, b| thresholds.is_red(r, g, b))),
+    )
+}
+
+fn is_not_white(r: u8, g: u8, b: u8) -> bool {
+    !(r == b && r == g && r == 255)
 }
 
 fn mask_to_raster_layer(mask: GrayImage) -> RasterLayer {
-    let mut res: Vec<[u8; 90]> = mask
+    let mut res: RasterLayer = mask
         .pixels()
-        .chunks(720)
+        .chunks(RASTER_LINE_DOTS as usize)
         .into_iter()
         .map(|line| {
-            line.chunks(8)
-                .into_iter()
-                .map(|chunk| {
-                    let mut res = 0;
-                    chunk.enumerate().for_each(|(i, px)| {
-                        if px.0[0] == 0 {
-                            res |= 1 << (7 - i);
-                        }
-                    });
-                    res
-                })
-                .collect_vec()
-                .try_into()
-                .unwrap()
+            let mut bytes = [0; RASTER_LINE_BYTES];
+            line.enumerate().for_each(|(i, px)| {
+                if px.0[0] == 0 {
+                    bytes[i / 8] |= 1 << (7 - i % 8);
+                }
+            });
+            bytes
         })
         .collect_vec();
     res.reverse();
@@ -86,9 +512,75 @@ fn mask_to_raster_layer(mask: GrayImage) -> RasterLayer {
 
 fn create_mask(
     img: &DynamicImage,
-    left_margin: u32,
-    filter: fn(r: u8, g: u8, b: u8) -> bool,
+    job: &PrintJob,
+    media_settings: &MediaSettings,
+    filter: impl Fn(u8, u8, u8) -> bool,
+) -> GrayImage {
+    let mut mask = match img {
+        // Grayscale images don't need the round trip through RGB. Images with an alpha channel
+        // have been flattened already.
+        DynamicImage::ImageLuma8(_) => {
+            let mut mask = img.to_luma8();
+            mask.pixels_mut().for_each(|px| {
+                let v = px.0[0];
+                if !filter(v, v, v) {
+                    px.0[0] = 255;
+                }
+            });
+            mask
+        }
+        _ => imageops::grayscale(&filter_pixels(img, filter)),
+    };
+    match job.dither_mode {
+        DitherMode::FloydSteinberg => {
+            // Dithering would not change an image that is black and white already
+            if !mask.pixels().all(|px| px.0[0] == 0 || px.0[0] == 255) {
+                imageops::dither(&mut mask, &BiLevel);
+            }
+        }
+        DitherMode::Threshold(cutoff) => mask
+            .pixels_mut()
+            .for_each(|px| px.0[0] = if px.0[0] < cutoff { 0 } else { 255 }),
+        DitherMode::None => {}
+    }
+    extend_to_raster_lines(&mask, job, media_settings)
+}
+
+/// Like [create_mask], but every pixel matching the filter is printed regardless of its
+/// brightness and the [dither_mode][PrintJob::dither_mode]. Used for the red layer, whose pixels
+/// are too dark to survive a low threshold.
+fn create_filter_mask(
+    img: &DynamicImage,
+    job: &PrintJob,
+    media_settings: &MediaSettings,
+    filter: impl Fn(u8, u8, u8) -> bool,
+) -> GrayImage {
+    let rgb = img.to_rgb8();
+    let mask = GrayImage::from_fn(rgb.width(), rgb.height(), |x, y| {
+        let &Rgb([r, g, b]) = rgb.get_pixel(x, y);
+        Luma([if filter(r, g, b) { 0 } else { 255 }])
+    });
+    extend_to_raster_lines(&mask, job, media_settings)
+}
+
+/// Places the mask at its position within full raster lines, leaving the margins blank.
+fn extend_to_raster_lines(
+    mask: &GrayImage,
+    job: &PrintJob,
+    media_settings: &MediaSettings,
 ) -> GrayImage {
+    let (w, h) = mask.dimensions();
+    let (_, right_margin) = margins(w, media_settings, job);
+    ImageBuffer::from_fn(RASTER_LINE_DOTS, h, |x, y| {
+        if (right_margin..(right_margin + w)).contains(&x) {
+            *mask.get_pixel(x - right_margin, y)
+        } else {
+            [255].into()
+        }
+    })
+}
+
+fn filter_pixels(img: &DynamicImage, filter: impl Fn(u8, u8, u8) -> bool) -> RgbImage {
     let (w, h) = img.dimensions();
     let mut filtered = RgbImage::new(w, h);
     img.to_rgb8()
@@ -102,15 +594,311 @@ fn create_mask(
                 [255, 255, 255]
             };
         });
-    let mut mask = imageops::grayscale(&filtered);
-    image::imageops::dither(&mut mask, &BiLevel);
-    let right_margin = 720 - left_margin - w;
-    let extended = ImageBuffer::from_fn(720, h, |x, y| {
-        if (right_margin..(right_margin + w)).contains(&x) {
-            *mask.get_pixel(x - right_margin, y)
-        } else {
-            [255].into()
+    filtered
+}
+
+/// Returns the amount of dots an image of the given width is moved from the left edge of the
+/// printable area due to its alignment.
+fn alignment_offset(
+    width: u32,
+    media_settings: &MediaSettings,
+    alignment: Option<Alignment>,
+) -> u32 {
+    let free = media_settings.width_dots.saturating_sub(width);
+    match alignment {
+        None | Some(Alignment::Left) => 0,
+        Some(Alignment::Center) => free / 2,
+        Some(Alignment::Right) => free,
+    }
+}
+
+/// Returns the amount of blank dots to the left and right of an image of the given width within a
+/// raster line.
+fn margins(width: u32, media_settings: &MediaSettings, job: &PrintJob) -> (u32, u32) {
+    let left = media_settings.left_margin + alignment_offset(width, media_settings, job.alignment);
+    // The offset must not move the image beyond the raster line
+    let max_left = RASTER_LINE_DOTS.saturating_sub(width);
+    let left =
+        (i64::from(left) + i64::from(job.margin_offset)).clamp(0, i64::from(max_left)) as u32;
+    (left, RASTER_LINE_DOTS.saturating_sub(left + width))
+}
+
+/// Returns the raster lines of the separator printed between pages, see
+/// [separator][PrintJob::separator].
+pub(crate) fn separator_lines(job: &PrintJob, media_settings: &MediaSettings) -> RasterLayer {
+    if media_settings.media_type != MediaType::Continuous || job.separator == Separator::None {
+        return Vec::new();
+    }
+    let (_, right_margin) = margins(media_settings.width_dots, media_settings, job);
+    let mut line = [0; RASTER_LINE_BYTES];
+    (right_margin..right_margin + media_settings.width_dots)
+        .filter(|dot| job.separator == Separator::Solid || dot / 8 % 2 == 0)
+        .for_each(|dot| line[dot as usize / 8] |= 1 << (7 - dot % 8));
+    // The separator is about 0.2mm thick at either resolution
+    let no_lines = if job.high_dpi || job.high_dpi_upscale {
+        4
+    } else {
+        2
+    };
+    vec![line; no_lines]
+}
+
+/// Returns the amount of blank dots to the left and right of the job's image after all image
+/// transformations.
+pub(crate) fn effective_margins(
+    job: &PrintJob,
+    media_settings: &MediaSettings,
+) -> Result<(u32, u32), BQLError> {
+    let img = prepare_image(&job.image, job, media_settings)?;
+    Ok(margins(img.width(), media_settings, job))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::GrayAlphaImage;
+
+    /// Returns the positions of all printed dots of a rasterized job, relative to the top left
+    /// corner of the printable area.
+    fn printed_dots(job: &PrintJob) -> Vec<(u32, u32)> {
+        let media_settings = MediaSettings::new(&job.media).unwrap();
+        let preview = job.raster_image().unwrap().to_image().to_luma8();
+        let start = RASTER_LINE_DOTS - media_settings.left_margin - media_settings.width_dots;
+        preview
+            .enumerate_pixels()
+            .filter(|(_, _, px)| px.0[0] == 0)
+            .map(|(x, y, _)| (x - start, y))
+            .collect()
+    }
+
+    fn black_image(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageLuma8(GrayImage::from_pixel(width, height, Luma([0])))
+    }
+
+    fn is_inside_circle((x, y): (u32, u32), diameter: u32) -> bool {
+        let radius = f64::from(diameter) / 2.0;
+        let (dx, dy) = (f64::from(x) + 0.5 - radius, f64::from(y) + 0.5 - radius);
+        dx * dx + dy * dy <= radius * radius
+    }
+
+    #[test]
+    fn circular_mask_clears_corners() {
+        let job = PrintJob::new(black_image(236, 236), Media::D24);
+        let dots = printed_dots(&job);
+        for corner in [(0, 0), (235, 0), (0, 235), (235, 235)] {
+            assert!(!dots.contains(&corner));
         }
-    });
-    extended
+        assert!(dots.contains(&(118, 118)));
+        assert!(dots.iter().all(|&dot| is_inside_circle(dot, 236)));
+    }
+
+    #[test]
+    fn circular_mask_follows_alignment() {
+        for alignment in [Alignment::Left, Alignment::Center, Alignment::Right] {
+            let job = PrintJob {
+                alignment: Some(alignment),
+                ..PrintJob::new(black_image(100, 236), Media::D24)
+            };
+            let dots = printed_dots(&job);
+            assert!(!dots.is_empty());
+            assert!(dots.iter().all(|&dot| is_inside_circle(dot, 236)));
+        }
+    }
+
+    #[test]
+    fn transparent_pixels_are_not_printed() {
+        let rgba = RgbaImage::from_fn(696, 20, |_, y| {
+            Rgba([0, 0, 0, if y < 10 { 255 } else { 0 }])
+        });
+        let job = PrintJob::new(DynamicImage::ImageRgba8(rgba), Media::C62);
+        let dots = printed_dots(&job);
+        assert_eq!(dots.len(), 696 * 10);
+        assert!(dots.iter().all(|&(_, y)| y < 10));
+    }
+
+    #[test]
+    fn grayscale_images_stay_grayscale() {
+        let gray = GrayAlphaImage::from_pixel(200, 236, LumaA([100, 128]));
+        let job = PrintJob {
+            gamma: 2.0,
+            alignment: Some(Alignment::Center),
+            ..PrintJob::new(DynamicImage::ImageLumaA8(gray), Media::D24)
+        };
+        let media_settings = MediaSettings::new(&job.media).unwrap();
+        let img = prepare_image(&job.image, &job, &media_settings).unwrap();
+        assert!(matches!(img.as_ref(), DynamicImage::ImageLuma8(_)));
+    }
+
+    #[test]
+    fn auto_resize_scales_continuous_images_to_width() {
+        for (width, height, lines) in [(1392, 200, 100), (348, 100, 200)] {
+            let job = PrintJob {
+                auto_resize: true,
+                ..PrintJob::new(black_image(width, height), Media::C62)
+            };
+            assert_eq!(job.raster_image().unwrap().no_lines(), lines);
+            assert_eq!(printed_dots(&job).len(), 696 * lines as usize);
+        }
+    }
+
+    #[test]
+    fn auto_resize_letterboxes_die_cut_images() {
+        for (width, height) in [(472, 236), (100, 50)] {
+            let job = PrintJob {
+                auto_resize: true,
+                circular_mask: Some(false),
+                ..PrintJob::new(black_image(width, height), Media::D24)
+            };
+            let dots = printed_dots(&job);
+            assert_eq!(job.raster_image().unwrap().no_lines(), 236);
+            assert_eq!(dots.len(), 236 * 118);
+            assert!(dots.iter().all(|&(_, y)| (59..177).contains(&y)));
+        }
+    }
+
+    #[test]
+    fn auto_resize_rejects_empty_images() {
+        for (width, height, media) in [(0, 10, Media::C62), (10, 0, Media::C62), (0, 0, Media::D24)]
+        {
+            let job = PrintJob {
+                auto_resize: true,
+                ..PrintJob::new(black_image(width, height), media)
+            };
+            assert_eq!(job.raster_image(), Err(BQLError::DimensionMismatch));
+        }
+    }
+
+    #[test]
+    fn dither_modes_produce_distinct_output() {
+        let gradient = GrayImage::from_fn(696, 50, |x, _| Luma([(x * 255 / 695) as u8]));
+        let raster_image = |dither_mode| {
+            let job = PrintJob {
+                dither_mode,
+                ..PrintJob::new(DynamicImage::ImageLuma8(gradient.clone()), Media::C62)
+            };
+            job.raster_image().unwrap()
+        };
+        let dithered = raster_image(DitherMode::FloydSteinberg);
+        let threshold = raster_image(DitherMode::Threshold(128));
+        let none = raster_image(DitherMode::None);
+        assert_ne!(dithered, threshold);
+        assert_ne!(dithered, none);
+        assert_ne!(threshold, none);
+        let below = |cutoff| gradient.pixels().filter(|px| px.0[0] < cutoff).count();
+        assert_eq!(threshold.dot_counts().0, below(128));
+        assert_eq!(none.dot_counts().0, below(1));
+    }
+
+    #[test]
+    fn threshold_applies_to_binary_images() {
+        let dots = |level, cutoff| {
+            let job = PrintJob {
+                dither_mode: DitherMode::Threshold(cutoff),
+                ..PrintJob::new(
+                    DynamicImage::ImageLuma8(GrayImage::from_pixel(696, 10, Luma([level]))),
+                    Media::C62,
+                )
+            };
+            job.raster_image().unwrap().dot_counts().0
+        };
+        assert_eq!(dots(0, 0), 0);
+        assert_eq!(dots(1, 0), 0);
+        assert_eq!(dots(0, 1), 696 * 10);
+        assert_eq!(dots(1, 1), 0);
+        assert_eq!(dots(255, 255), 0);
+    }
+
+    #[test]
+    fn red_layer_ignores_dither_mode() {
+        let red = RgbImage::from_pixel(696, 20, Rgb([255, 0, 0]));
+        for dither_mode in [
+            DitherMode::FloydSteinberg,
+            DitherMode::Threshold(0),
+            DitherMode::Threshold(54),
+            DitherMode::Threshold(128),
+            DitherMode::None,
+        ] {
+            let job = PrintJob {
+                dither_mode,
+                ..PrintJob::new(DynamicImage::ImageRgb8(red.clone()), Media::C62R)
+            };
+            let dot_counts = job.raster_image().unwrap().dot_counts();
+            assert_eq!(dot_counts, (0, 696 * 20), "{dither_mode:?}");
+        }
+    }
+
+    #[test]
+    fn alignment_places_narrow_images() {
+        // D17x54 has no media settings yet, so the narrow image is placed on D24 instead
+        for (alignment, left) in [
+            (Alignment::Left, 0),
+            (Alignment::Center, 35),
+            (Alignment::Right, 71),
+        ] {
+            let job = PrintJob {
+                alignment: Some(alignment),
+                circular_mask: Some(false),
+                ..PrintJob::new(black_image(165, 236), Media::D24)
+            };
+            // Raster lines are mirrored, so the physical position is counted from the end
+            let columns = printed_dots(&job)
+                .into_iter()
+                .map(|(x, _)| 235 - x)
+                .collect::<std::collections::BTreeSet<_>>();
+            assert_eq!(columns, (left..left + 165).collect());
+        }
+    }
+
+    #[test]
+    fn grayscale_images_match_direct_threshold() {
+        let gray = GrayImage::from_fn(696, 20, |x, y| Luma([((x * 7 + y * 31) % 256) as u8]));
+        let binary = GrayImage::from_fn(696, 20, |x, y| {
+            Luma([if (x / 3 + y) % 4 == 0 { 0 } else { 255 }])
+        });
+        let dark_pixels = |img: &GrayImage, cutoff: u8| {
+            img.enumerate_pixels()
+                .filter(|(_, _, px)| px.0[0] < cutoff)
+                .map(|(x, y, _)| (x, y))
+                .collect::<Vec<_>>()
+        };
+        let threshold_job = PrintJob {
+            dither_mode: DitherMode::Threshold(100),
+            ..PrintJob::new(DynamicImage::ImageLuma8(gray.clone()), Media::C62)
+        };
+        assert_eq!(printed_dots(&threshold_job), dark_pixels(&gray, 100));
+        // Binary images are not dithered
+        let binary_job = PrintJob::new(DynamicImage::ImageLuma8(binary.clone()), Media::C62);
+        assert_eq!(printed_dots(&binary_job), dark_pixels(&binary, 1));
+    }
+
+    #[test]
+    fn margin_offset_shifts_image_right() {
+        let columns = |margin_offset| {
+            let job = PrintJob {
+                alignment: Some(Alignment::Left),
+                circular_mask: Some(false),
+                margin_offset,
+                ..PrintJob::new(black_image(165, 236), Media::D24)
+            };
+            // Raster lines are mirrored, so the physical position is counted from the end
+            printed_dots(&job)
+                .into_iter()
+                .map(|(x, _)| 235 - x)
+                .collect::<std::collections::BTreeSet<_>>()
+        };
+        assert_eq!(columns(0), (0..165).collect());
+        assert_eq!(columns(10), (10..175).collect());
+    }
+
+    #[test]
+    fn margin_offset_never_moves_image_beyond_raster_line() {
+        for (margin_offset, margins) in [(1000, (555, 0)), (-1000, (0, 555))] {
+            let job = PrintJob {
+                alignment: Some(Alignment::Left),
+                margin_offset,
+                ..PrintJob::new(black_image(165, 236), Media::D24)
+            };
+            assert_eq!(job.effective_margins().unwrap(), margins);
+        }
+    }
 }